@@ -1,4 +1,6 @@
+use clap::{error::ErrorKind, CommandFactory};
 use clap_derive::Parser;
+use mono_diagram::OnError;
 
 #[derive(Debug, Parser)]
 #[command(author, version, about, long_about = None)]
@@ -12,7 +14,98 @@ pub struct Cli {
     /// Copy the output to your computer clipboard
     #[arg(short, long)]
     pub copy: bool,
+    /// Write the output to a file instead of the console
+    #[arg(short, long, value_name = "FILE")]
+    pub output: Option<String>,
+    /// Update the diagrams embedded in the input file in place
+    ///
+    /// Diagrams are rendered for every region between 'mono-diagram:begin' and
+    /// 'mono-diagram:end' markers, and only the rendered part of each region is rewritten
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = ["prefix", "copy", "output", "watch"],
+        requires = "file_path"
+    )]
+    pub in_place: bool,
+    /// Treat the input file as a Markdown document
+    ///
+    /// Every code block tagged 'mono-diagram' is rendered, and the document is written
+    /// with the code blocks replaced by the rendered diagrams
+    #[arg(short, long, conflicts_with_all = ["prefix", "in_place", "watch"])]
+    pub markdown: bool,
+    /// Keep the source code blocks and insert the rendered diagrams after them
+    #[arg(short, long, requires = "markdown")]
+    pub keep_source: bool,
+    /// Watch the input file and re-render the diagrams whenever it changes
+    ///
+    /// The last successful render is kept on screen when the file has errors
+    #[arg(
+        short,
+        long,
+        conflicts_with_all = ["copy", "output", "in_place"],
+        requires = "file_path"
+    )]
+    pub watch: bool,
+    /// What to do when a diagram fails: abort, inline or skip
+    ///
+    /// 'abort' stops at the first failed diagram. 'inline' renders the other diagrams and
+    /// draws a box with the error in place of each failed one. 'skip' renders the other
+    /// diagrams and leaves failed ones out. The errors are reported after the output
+    #[arg(
+        long,
+        value_name = "MODE",
+        default_value = "abort",
+        conflicts_with = "watch"
+    )]
+    pub on_error: OnError,
     /// The path to the input file
+    ///
+    /// Read from stdin if the path is '-' or not given
     #[arg()]
-    pub file_path: String,
+    pub file_path: Option<String>,
+}
+
+impl Cli {
+    /// Check the arguments clap cannot check by itself
+    pub fn validate(self) -> Result<Self, clap::Error> {
+        if self.in_place && self.file_path.as_deref() == Some("-") {
+            return Err(Cli::command().error(
+                ErrorKind::ValueValidation,
+                "cannot update stdin in place, please specify a file for '--in-place'",
+            ));
+        }
+        Ok(self)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use clap::Parser;
+
+    use super::*;
+
+    #[test]
+    fn watch_only_previews_on_screen() {
+        for args in [&["-w", "-o", "out.txt"][..], &["-w", "-i"], &["-w", "-c"]] {
+            let args = ["mono-diagram"].iter().chain(args).chain(&["script.txt"]);
+            let e = Cli::try_parse_from(args).unwrap_err();
+            assert_eq!(e.kind(), ErrorKind::ArgumentConflict);
+        }
+        assert!(
+            Cli::try_parse_from(["mono-diagram", "-w", "script.txt"])
+                .unwrap()
+                .watch
+        );
+    }
+
+    #[test]
+    fn in_place_needs_a_file() {
+        let cli = Cli::try_parse_from(["mono-diagram", "-i", "-"]).unwrap();
+        let e = cli.validate().unwrap_err();
+        assert_eq!(e.kind(), ErrorKind::ValueValidation);
+        assert_eq!(e.exit_code(), 2);
+        let cli = Cli::try_parse_from(["mono-diagram", "-i", "doc.md"]).unwrap();
+        assert!(cli.validate().is_ok());
+    }
 }
@@ -1,48 +1,94 @@
 use std::str::FromStr;
 
 use anyhow::Result;
+use pest::Parser;
 use pest_derive::Parser;
 
+use crate::error::{ErrorKind, ScriptError};
+
 #[derive(Debug, Default)]
 pub struct Attrib {
     pub style: Style,
+    /// Seed for the starting order of dag layouts
+    pub seed: u64,
+    /// Direction in which the levels of dag layouts go
+    pub direction: Direction,
+    /// Widest a dag node can be before its text wraps, no limit if 0
+    pub max_node_width: usize,
 }
 
+/// Set the attributes parsed from `$input`, ignoring unknown attributes
+///
+/// Values that the lenient attributes do not take are ignored as well, while the others
+/// report them as errors.
 macro_rules! parse_attrib {
-    ($input:expr, $($field:ident), *) => {{
-        let mut result = Attrib::default();
-
-        let input_trimmed = $input.trim().trim_end_matches("}").trim_start_matches("{");
-        let pairs: Vec<&str> = input_trimmed.split(',').map(|s| s.trim()).collect();
-        for pair in pairs {
-            if let Some((key, value)) = pair.split_once(':') {
-                let key = key.trim().to_ascii_lowercase();
-                let value = value.trim();
-
-                match key.as_str() {
-                    $(
-                        stringify!($field) => {
-                            if let Ok(val) = value.parse() {
-                                result.$field = val;
-                            }
+    (
+        $result:expr,
+        $input:expr,
+        $($lenient:ident: $lenient_ty:ty),*;
+        $($field:ident: $ty:ty => $expected:literal),*
+    ) => {{
+        let attribs = AttribParser::parse(Rule::attribs, $input)
+            .map_err(|e| ScriptError::from_pest(e, "attribute"))?
+            .next()
+            .unwrap();
+        for attrib in attribs.into_inner().filter(|p| p.as_rule() == Rule::attrib) {
+            let mut attrib = attrib.into_inner();
+            let (key, value) = (attrib.next().unwrap(), attrib.next().unwrap());
+            match key.as_str().to_ascii_lowercase().as_str() {
+                $(
+                    stringify!($lenient) => {
+                        if let Ok(val) = value.as_str().parse::<$lenient_ty>() {
+                            $result.$lenient = val;
                         }
-                    ),*
-                    _ => {}
-                }
+                    }
+                )*
+                $(
+                    stringify!($field) => {
+                        let val = value.as_str().parse::<$ty>().map_err(|_| {
+                            ScriptError::new(
+                                ErrorKind::Parsing,
+                                format!(
+                                    "invalid {}: {}, expected {}",
+                                    stringify!($field),
+                                    value.as_str(),
+                                    $expected
+                                ),
+                            )
+                            .with_span(value.as_span())
+                        })?;
+                        $result.$field = val;
+                    }
+                )*
+                _ => {}
             }
         }
-        result
     }};
 }
 
 impl Attrib {
+    /// Parse attributes written as `{key: value, ...}`
     pub fn parse_from_str(input: &str) -> Result<Self> {
-        let attribs = parse_attrib!(input, style);
+        let mut attribs = Attrib::default();
+        attribs.update_from_str(input)?;
         Ok(attribs)
     }
+
+    /// Set the attributes written as `{key: value, ...}`, keeping the others
+    pub fn update_from_str(&mut self, input: &str) -> Result<()> {
+        parse_attrib!(
+            self,
+            input,
+            style: Style;
+            seed: u64 => "a non-negative integer",
+            direction: Direction => "one of tb, bt, lr, rl",
+            max_node_width: usize => "a non-negative integer"
+        );
+        Ok(())
+    }
 }
 
-#[derive(Default, Debug, PartialEq, Eq)]
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Style {
     #[default]
     Ascii,
@@ -53,9 +99,9 @@ impl FromStr for Style {
     type Err = ParseStyleError;
 
     fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
-        if s.to_ascii_lowercase() == "ascii" {
+        if s.eq_ignore_ascii_case("ascii") {
             Ok(Self::Ascii)
-        } else if s.to_ascii_lowercase() == "unicode" {
+        } else if s.eq_ignore_ascii_case("unicode") {
             Ok(Self::Unicode)
         } else {
             Err(ParseStyleError)
@@ -66,6 +112,67 @@ impl FromStr for Style {
 #[derive(Debug, PartialEq, Eq)]
 pub struct ParseStyleError;
 
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    #[default]
+    TopBottom,
+    BottomTop,
+    LeftRight,
+    RightLeft,
+}
+
+impl Direction {
+    pub fn is_horizontal(&self) -> bool {
+        matches!(self, Self::LeftRight | Self::RightLeft)
+    }
+}
+
+impl FromStr for Direction {
+    type Err = ParseDirectionError;
+
+    fn from_str(s: &str) -> std::prelude::v1::Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "tb" | "td" => Ok(Self::TopBottom),
+            "bt" => Ok(Self::BottomTop),
+            "lr" => Ok(Self::LeftRight),
+            "rl" => Ok(Self::RightLeft),
+            _ => Err(ParseDirectionError),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseDirectionError;
+
 #[derive(Parser)]
 #[grammar = "mono-diagram/grammar/attrib.pest"]
 struct AttribParser;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attributes_can_have_empty_entries() {
+        for input in ["{, style: unicode}", "{style: unicode,, }", "{,}", "{}"] {
+            let attribs = Attrib::parse_from_str(input).unwrap();
+            let style = if input.contains("unicode") {
+                Style::Unicode
+            } else {
+                Style::Ascii
+            };
+            assert_eq!(attribs.style, style, "{}", input);
+        }
+    }
+
+    #[test]
+    fn unknown_attributes_are_ignored_and_new_ones_checked() {
+        let attribs = Attrib::parse_from_str("{color: red, style: fancy, seed: 3}").unwrap();
+        assert_eq!((attribs.style, attribs.seed), (Style::Ascii, 3));
+        for input in ["{seed: -1}", "{direction: up}", "{max_node_width: wide}"] {
+            let e = Attrib::parse_from_str(input).unwrap_err();
+            let e = e.downcast::<ScriptError>().unwrap();
+            assert!(e.message.starts_with("invalid "), "{}", input);
+        }
+    }
+}
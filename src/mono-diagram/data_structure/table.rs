@@ -1,6 +1,11 @@
-use std::fmt::{Debug, Display};
+use std::{
+    cmp::max,
+    fmt::{Debug, Display},
+};
 
-#[derive(Default, Debug)]
+use crate::utils::display_width;
+
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct Table<T>
 where
     T: Display + Default + Debug,
@@ -10,10 +15,39 @@ where
     pub height: usize,
 }
 
-#[derive(Default, Debug)]
+#[derive(Default, Debug, Clone, PartialEq)]
 pub struct TableCell<T>
 where
     T: Display + Default + Debug,
 {
     pub value: T,
 }
+
+impl<T> Table<T>
+where
+    T: Display + Default + Debug,
+{
+    /// Display width of each column, which is the one of its widest line
+    pub fn col_width(&self) -> Vec<usize> {
+        let mut col_width = vec![0; self.width];
+        for row in self.cells.iter() {
+            for (idx, col) in row.iter().enumerate() {
+                let value = col.value.to_string();
+                col_width[idx] = value.lines().map(display_width).fold(col_width[idx], max);
+            }
+        }
+        col_width
+    }
+
+    /// Lines each row takes, which is the most lines of its cells, and at least one
+    pub fn row_height(&self) -> Vec<usize> {
+        self.cells
+            .iter()
+            .map(|row| {
+                row.iter()
+                    .map(|cell| cell.value.to_string().split('\n').count())
+                    .fold(1, max)
+            })
+            .collect()
+    }
+}
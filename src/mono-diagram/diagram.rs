@@ -3,9 +3,11 @@ use anyhow::Result;
 use crate::attrib::Attrib;
 
 pub mod binary_tree_diagram;
+pub mod class_diagram;
 pub mod dag_diagram;
 pub mod gantt_diagram;
 pub mod grid_diagram;
+pub mod sequence_diagram;
 pub mod table_diagram;
 pub mod timeline_diagram;
 
@@ -13,4 +15,9 @@ pub mod timeline_diagram;
 pub trait Diagram {
     fn parse_from_str(&mut self, input: &str, attribs: Attrib) -> Result<()>;
     fn write(&self) -> Result<Vec<u8>>;
+
+    /// Attributes the diagram has when the script does not give them
+    fn default_attribs(&self) -> Attrib {
+        Attrib::default()
+    }
 }
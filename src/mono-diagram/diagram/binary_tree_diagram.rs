@@ -4,14 +4,15 @@ use std::{
     io::Write as _,
 };
 
-use anyhow::{Error, Result};
-use pest::{iterators::Pairs, Parser};
+use anyhow::Result;
+use pest::{iterators::Pairs, Parser, Span};
 use pest_derive::Parser;
 
 use crate::{
     attrib::{Attrib, Style},
     data_structure::binary_tree::TreeNode,
-    utils::pad_string_center,
+    error::{ErrorKind, ScriptError},
+    utils::{pad_string_center, unescape},
 };
 
 use super::Diagram;
@@ -24,16 +25,13 @@ pub struct BinaryTreeDiagram {
 
 impl Diagram for BinaryTreeDiagram {
     fn parse_from_str(&mut self, input: &str, attribs: Attrib) -> Result<()> {
-        let mut root: &str = "";
         let mut relationship_map: HashMap<&str, (Option<&str>, Option<&str>)> = HashMap::new();
-        let mut assign_map: HashMap<&str, &str> = HashMap::new();
+        let mut assign_map: HashMap<&str, String> = HashMap::new();
+        // Parents with the span of their name, and children with the parent they belong to
+        let mut parents: Vec<Span> = Vec::new();
+        let mut child_of: HashMap<&str, &str> = HashMap::new();
         let diagram = BinaryTreeParser::parse(Rule::diagram, input)
-            .map_err(|e| {
-                Error::msg(format!(
-                    "parsing error: incorrect binary_tree grammar, context: {}",
-                    e.line()
-                ))
-            })?
+            .map_err(|e| ScriptError::from_pest(e, "binary_tree"))?
             .next()
             .unwrap();
         for line in diagram.into_inner() {
@@ -41,32 +39,92 @@ impl Diagram for BinaryTreeDiagram {
                 Rule::assign => {
                     let mut statement = line.into_inner();
                     let variable = statement.next().unwrap().as_str();
-                    let value = statement.next().unwrap().as_str();
+                    let value = unescape(statement.next().unwrap().as_str());
                     assign_map.insert(variable, value);
                 }
                 Rule::relationship => {
                     let mut statement = line.into_inner();
-                    let node_variable_name = statement.next().unwrap().as_str();
-                    let childs = parse_childs(statement.next().unwrap().into_inner());
-                    relationship_map.insert(node_variable_name, childs);
-                    if root.is_empty() {
-                        root = node_variable_name;
+                    let node = statement.next().unwrap();
+                    let childs = statement.next().unwrap();
+                    if relationship_map.contains_key(node.as_str()) {
+                        return Err(ScriptError::new(
+                            ErrorKind::Diagram,
+                            format!(
+                                "node has its children given more than once: {}",
+                                node.as_str()
+                            ),
+                        )
+                        .with_span(node.as_span())
+                        .into());
                     }
-                    if let Some(lchild) = childs.0 {
-                        if root == lchild {
-                            root = node_variable_name;
-                        }
-                    }
-                    if let Some(rchild) = childs.1 {
-                        if root == rchild {
-                            root = node_variable_name;
+                    for child in childs.clone().into_inner() {
+                        if let Some(parent) = child_of.insert(child.as_str(), node.as_str()) {
+                            return Err(ScriptError::new(
+                                ErrorKind::Diagram,
+                                format!(
+                                    "node is a child of more than one node: {}, already a child of {}",
+                                    child.as_str(),
+                                    parent
+                                ),
+                            )
+                            .with_span(child.as_span())
+                            .into());
                         }
                     }
+                    relationship_map.insert(node.as_str(), parse_childs(childs.into_inner()));
+                    parents.push(node.as_span());
                 }
                 _ => (),
             }
         }
 
+        // The root is the one parent that is not a child, nodes under it cannot be above it
+        let mut roots = parents
+            .iter()
+            .filter(|p| !child_of.contains_key(p.as_str()));
+        let root = match (roots.next(), roots.next()) {
+            (Some(root), None) => root.as_str(),
+            (Some(_), Some(other)) => {
+                return Err(ScriptError::new(
+                    ErrorKind::Diagram,
+                    format!("tree has more than one root: {}", other.as_str()),
+                )
+                .with_span(*other)
+                .into())
+            }
+            (None, _) if parents.is_empty() => {
+                return Err(
+                    ScriptError::new(ErrorKind::Diagram, "tree has no relationships").into(),
+                )
+            }
+            (None, _) => {
+                return Err(ScriptError::new(
+                    ErrorKind::Diagram,
+                    format!("node is a descendant of itself: {}", parents[0].as_str()),
+                )
+                .with_span(parents[0])
+                .into())
+            }
+        };
+
+        // Nodes in a cycle of their own are children, but cannot be reached from the root
+        let mut reached = vec![root];
+        let mut i = 0;
+        while let Some(node) = reached.get(i) {
+            if let Some((l, r)) = relationship_map.get(node) {
+                reached.extend(l.iter().chain(r.iter()));
+            }
+            i += 1;
+        }
+        if let Some(p) = parents.iter().find(|p| !reached.contains(&p.as_str())) {
+            return Err(ScriptError::new(
+                ErrorKind::Diagram,
+                format!("node is a descendant of itself: {}", p.as_str()),
+            )
+            .with_span(*p)
+            .into());
+        }
+
         let tree = construct_tree(root, &relationship_map, &assign_map);
         self.data = tree;
         self.attribs = attribs;
@@ -96,7 +154,9 @@ impl BinaryTreeDiagram {
             let width = spacing[i + 2];
             if i != degree - 1 {
                 // Print arrow
-                assert!(next.len() % 2 == 0);
+                if next.len() % 2 != 0 {
+                    return Err(unpaired_children());
+                }
                 for pair in next.chunks(2) {
                     (0..=spacing[i + 1]).try_for_each(|_| write!(&mut buffer, " "))?;
                     if pair[0].is_some() {
@@ -123,10 +183,11 @@ impl BinaryTreeDiagram {
                 if let Some(n) = n {
                     let l_pad = if n.lnode.is_none() { ' ' } else { '_' };
                     let r_pad = if n.rnode.is_none() { ' ' } else { '_' };
+                    let value = pad_string_center(&n.value, spacing[i], l_pad, r_pad);
                     write!(
                         &mut buffer,
-                        "{:^width$} ",
-                        pad_string_center(&n.value, spacing[i], l_pad, r_pad)
+                        "{} ",
+                        pad_string_center(&value, width, ' ', ' ')
                     )
                 } else {
                     write!(&mut buffer, "{:^width$} ", "")
@@ -171,7 +232,9 @@ impl BinaryTreeDiagram {
             let width = spacing[i + 2];
             if i != degree - 1 {
                 // Print arrow
-                assert!(next.len() % 2 == 0);
+                if next.len() % 2 != 0 {
+                    return Err(unpaired_children());
+                }
                 for pair in next.chunks(2) {
                     (0..spacing[i + 1]).try_for_each(|_| write!(&mut buffer, " "))?;
                     let has_left = pair[0].is_some();
@@ -205,10 +268,11 @@ impl BinaryTreeDiagram {
             // Print data
             next.iter().try_for_each(|n| {
                 if let Some(n) = n {
+                    let value = pad_string_center(&n.value, spacing[i], ' ', ' ');
                     write!(
                         &mut buffer,
-                        "{:^width$} ",
-                        pad_string_center(&n.value, spacing[i], ' ', ' ')
+                        "{} ",
+                        pad_string_center(&value, width, ' ', ' ')
                     )
                 } else {
                     write!(&mut buffer, "{:^width$} ", "")
@@ -240,7 +304,16 @@ impl BinaryTreeDiagram {
     }
 }
 
-fn parse_childs(pairs: Pairs<Rule>) -> (Option<&str>, Option<&str>) {
+/// Error for a level that does not hold a left and a right child for each node above
+fn unpaired_children() -> anyhow::Error {
+    ScriptError::new(
+        ErrorKind::Diagram,
+        "tree has a node without two child slots",
+    )
+    .into()
+}
+
+fn parse_childs(pairs: Pairs<'_, Rule>) -> (Option<&str>, Option<&str>) {
     let mut res = (None, None);
     for pair in pairs {
         match pair.as_rule() {
@@ -256,7 +329,7 @@ fn parse_childs(pairs: Pairs<Rule>) -> (Option<&str>, Option<&str>) {
 fn construct_tree<'a>(
     root: &'a str,
     rm: &'a HashMap<&'a str, (Option<&'a str>, Option<&'a str>)>,
-    am: &'a HashMap<&'a str, &'a str>,
+    am: &'a HashMap<&'a str, String>,
 ) -> Box<TreeNode<String>> {
     construct_tree_helper(root, rm, am, &RefCell::new(HashSet::new()))
 }
@@ -264,7 +337,7 @@ fn construct_tree<'a>(
 fn construct_tree_helper<'a>(
     root: &'a str,
     rm: &'a HashMap<&'a str, (Option<&'a str>, Option<&'a str>)>,
-    am: &'a HashMap<&'a str, &'a str>,
+    am: &'a HashMap<&'a str, String>,
     set: &RefCell<HashSet<String>>,
 ) -> Box<TreeNode<String>> {
     set.borrow_mut().insert(root.to_string());
@@ -284,7 +357,7 @@ fn construct_tree_helper<'a>(
         }
         Box::new(TreeNode::new(
             if let Some(value) = am.get(&root) {
-                (*value).to_string()
+                value.to_string()
             } else {
                 root.to_string()
             },
@@ -293,7 +366,7 @@ fn construct_tree_helper<'a>(
         ))
     } else {
         Box::new(TreeNode::new_leaf(if let Some(value) = am.get(&root) {
-            (*value).to_string()
+            value.to_string()
         } else {
             root.to_string()
         }))
@@ -0,0 +1,249 @@
+use pest::{Parser, Span};
+use pest_derive::Parser;
+
+use crate::{
+    attrib::Attrib,
+    data_structure::table::{Table, TableCell},
+    error::{ErrorKind, ScriptError},
+    utils::unescape,
+};
+
+use super::{
+    dag_diagram::{DagGraph, GraphScript},
+    Diagram,
+};
+
+/// UML class diagram, whose classes are record nodes laid out like a digraph
+#[derive(Debug, Default)]
+pub struct ClassDiagram {
+    graph: DagGraph,
+}
+
+impl Diagram for ClassDiagram {
+    fn parse_from_str(&mut self, input: &str, attribs: Attrib) -> anyhow::Result<()> {
+        let mut graph = DagGraph::class();
+        let mut script = GraphScript::default();
+        let mut declared: Vec<Span> = Vec::new();
+        let diagram = ClassDiagramParser::parse(Rule::diagram, input)
+            .map_err(|e| ScriptError::from_pest(e, "class"))?
+            .next()
+            .unwrap();
+        for line in diagram.into_inner() {
+            match line.as_rule() {
+                Rule::class => {
+                    let mut class = line.into_inner().skip(1);
+                    let name = class.next().unwrap().as_span();
+                    if declared.iter().any(|d| d.as_str() == name.as_str()) {
+                        return Err(ScriptError::new(
+                            ErrorKind::Diagram,
+                            format!("class is defined more than once: {}", name.as_str()),
+                        )
+                        .with_span(name)
+                        .into());
+                    }
+                    // Members with a parameter list are methods, the others are fields
+                    let (methods, fields): (Vec<String>, Vec<String>) = class
+                        .map(|m| unescape(m.as_str()))
+                        .partition(|m| m.contains('('));
+                    script
+                        .records
+                        .insert(name.as_str(), class_table(name.as_str(), fields, methods));
+                    declared.push(name);
+                }
+                Rule::relationship => {
+                    let mut r = line.into_inner();
+                    let from = r.next().unwrap().as_str();
+                    let mut edge = graph.edge(r.next().unwrap().as_str()).unwrap();
+                    let to = r.next().unwrap().as_str();
+                    edge.label = r.next().map(|l| unescape(l.as_str()));
+                    script.edges.push((from, to, edge));
+                }
+                _ => (),
+            }
+        }
+        script.nodes = declared.iter().map(|d| d.as_str()).collect();
+        // Classes that are only named in relationships are drawn with their name alone
+        for (s, t, _) in script.edges.iter() {
+            for name in [s, t] {
+                script
+                    .records
+                    .entry(name)
+                    .or_insert_with(|| class_table(name, Vec::new(), Vec::new()));
+            }
+        }
+        graph.lay_out(script, attribs)?;
+        self.graph = graph;
+        Ok(())
+    }
+
+    fn default_attribs(&self) -> Attrib {
+        self.graph.default_attribs()
+    }
+
+    fn write(&self) -> anyhow::Result<Vec<u8>> {
+        self.graph.write()
+    }
+}
+
+/// Record of a class, with its name, fields and methods in compartments of their own
+fn class_table(name: &str, fields: Vec<String>, methods: Vec<String>) -> Table<String> {
+    let cells: Vec<Vec<TableCell<String>>> = [vec![name.to_string()], fields, methods]
+        .into_iter()
+        .filter(|c| !c.is_empty())
+        .map(|c| {
+            vec![TableCell {
+                value: c.join("\n"),
+            }]
+        })
+        .collect();
+    Table {
+        height: cells.len(),
+        width: 1,
+        cells,
+    }
+}
+
+#[derive(Parser)]
+#[grammar = "mono-diagram/grammar/class.pest"]
+struct ClassDiagramParser;
+
+#[cfg(test)]
+mod tests {
+    use crate::attrib::{Direction, Style};
+
+    use super::*;
+
+    fn render(script: &str, style: Style, direction: Direction) -> String {
+        let mut class = ClassDiagram::default();
+        let mut attribs = class.default_attribs();
+        attribs.style = style;
+        attribs.direction = direction;
+        class.parse_from_str(script, attribs).unwrap();
+        String::from_utf8(class.write().unwrap()).unwrap()
+    }
+
+    fn lines(output: String) -> Vec<String> {
+        output.lines().map(String::from).collect()
+    }
+
+    #[test]
+    fn members_with_parameters_are_methods() {
+        let table = class_table("A", Vec::new(), vec!["run()".to_string()]);
+        let cells: Vec<&str> = table.cells.iter().map(|r| r[0].value.as_str()).collect();
+        assert_eq!(cells, ["A", "run()"]);
+        assert_eq!(
+            lines(render(
+                "class A {\n  run()\n  x: i32\n  y\n}\nA --> B",
+                Style::Ascii,
+                Direction::TopBottom
+            )),
+            [
+                "+--------+ ",
+                "|   A    | ",
+                "+--------+ ",
+                "| x: i32 | ",
+                "| y      | ",
+                "+--------+ ",
+                "| run()  | ",
+                "++-------+ ",
+                "+v--+      ",
+                "| B |      ",
+                "+---+      ",
+            ]
+        );
+    }
+
+    #[test]
+    fn classes_are_defined_once() {
+        let mut class = ClassDiagram::default();
+        let e = class
+            .parse_from_str("class A\nclass A", class.default_attribs())
+            .unwrap_err();
+        let e = e.downcast::<ScriptError>().unwrap();
+        assert_eq!(e.message, "class is defined more than once: A");
+    }
+
+    #[test]
+    fn classes_without_relationships_are_drawn() {
+        assert_eq!(
+            render("class A { x }", Style::Ascii, Direction::TopBottom),
+            "+---+ \n| A | \n+---+ \n| x | \n+---+ \n"
+        );
+    }
+
+    #[test]
+    fn classes_can_be_associated_with_themselves() {
+        let output = render(
+            "class Node { next }\nNode --> Node",
+            Style::Ascii,
+            Direction::TopBottom,
+        );
+        assert!(
+            output.contains("| Node ++") && output.contains("<+"),
+            "{}",
+            output
+        );
+    }
+
+    #[test]
+    fn relationships_with_other_heads_are_kept_apart() {
+        let output = render("A <|-- B\nA *-- B", Style::Ascii, Direction::TopBottom);
+        assert!(output.contains("+A*-"), "{}", output);
+    }
+
+    #[test]
+    fn triangles_are_told_from_arrows() {
+        let output = render("A <|-- B\nA --> B", Style::Ascii, Direction::LeftRight);
+        assert!(
+            output.contains("<|-") && output.contains("->"),
+            "{}",
+            output
+        );
+        let output = render("A <|-- A", Style::Ascii, Direction::TopBottom);
+        assert!(output.contains("<|+"), "{}", output);
+    }
+
+    #[test]
+    fn class_renders_in_both_styles() {
+        let script = "class Animal {\n  name: String\n  speak()\n}\nAnimal <|-- Dog\n\
+            Animal <|-- Cat\nOwner o-- Dog : owns\nDog *-- Tail\nDog --> Owner\n";
+        assert_eq!(
+            lines(render(script, Style::Unicode, Direction::TopBottom)),
+            [
+                "┌───────────────┐     ",
+                "│    Animal     │     ",
+                "├───────────────┤     ",
+                "│ name: String  │     ",
+                "├───────────────┤     ",
+                "│ speak()       │     ",
+                "└△─────────────△┘     ",
+                "┌┴──────────┐ ┌┴────┐ ",
+                "│    Dog    │ │ Cat │ ",
+                "└┬┬────────◆┘ └─────┘ ",
+                " ││ owns   │          ",
+                "┌V◇─────┐ ┌┴─────┐    ",
+                "│ Owner │ │ Tail │    ",
+                "└───────┘ └──────┘    ",
+            ]
+        );
+        assert_eq!(
+            lines(render(script, Style::Ascii, Direction::TopBottom)),
+            [
+                "+---------------+     ",
+                "|    Animal     |     ",
+                "+---------------+     ",
+                "| name: String  |     ",
+                "+---------------+     ",
+                "| speak()       |     ",
+                "+A-------------A+     ",
+                "++----------+ ++----+ ",
+                "|    Dog    | | Cat | ",
+                "+++--------*+ +-----+ ",
+                " || owns   |          ",
+                "+vo-----+ ++-----+    ",
+                "| Owner | | Tail |    ",
+                "+-------+ +------+    ",
+            ]
+        );
+    }
+}
@@ -1,48 +1,273 @@
 use std::{
+    borrow::Cow,
     cmp::{max, min},
-    collections::{HashMap, HashSet},
+    collections::HashMap,
+    str::FromStr,
 };
 
-use anyhow::Error;
-use pest::Parser;
+use pest::{iterators::Pair, Parser, Span};
 use pest_derive::Parser;
 use petgraph::{
     algo::is_cyclic_directed,
     graph::{DiGraph, EdgeIndex, NodeIndex},
+    unionfind::UnionFind,
     visit::EdgeRef,
     Direction::{self, Incoming, Outgoing},
 };
-use rand::{thread_rng, Rng};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 
-use crate::attrib::Attrib;
+use crate::{
+    attrib::{self, Attrib, Style},
+    data_structure::table::{Table, TableCell},
+    error::{ErrorKind, ScriptError},
+    utils::{display_width, unescape, wrap_text},
+};
 
-use super::Diagram;
+use super::{table_diagram::draw_table, Diagram};
 
-const PALETTE: &str = "┌─┐││└─┘┬│─└┐┌┘V";
+// The layout always runs top to bottom, then the buffer is transposed or flipped for the
+// other directions, with palettes rotated the same way. Palettes hold the edges of nodes,
+// then the ends of connections: junctions, arrowheads, and the heads of class relationships,
+// and the ends of loops on the right side of a node. Last are the tails that triangles
+// pointing sideways have in ascii, on the line just before them, to tell them from arrows.
+const PALETTE_UNICODE: &str = "┌─┐││└─┘┬V^┴▽△◆◇├<◁  ";
+const PALETTE_UNICODE_BT: &str = "└─┘││┌─┐┴^V┬△▽◆◇├<◁  ";
+const PALETTE_UNICODE_LR: &str = "┌│└──┐│┘├><┤▷◁◆◇┬^△  ";
+const PALETTE_UNICODE_RL: &str = "┐│┘──┌│└┤<>├◁▷◆◇┬^△  ";
+const PALETTE_ASCII: &str = "+-+||+-++v^+VA*o+<< |";
+const PALETTE_ASCII_BT: &str = "+-+||+-++^v+AV*o+<< |";
+const PALETTE_ASCII_LR: &str = "+|+--+|++><+><*o+^A| ";
+const PALETTE_ASCII_RL: &str = "+|+--+|++<>+<>*o+^A| ";
 type Digraph = DiGraph<NodeData, EdgeData>;
+/// Connections and spacing of each gap between levels, columns put in, and edge labels
+type Routes = (Vec<Vec<Connection>>, Vec<(isize, isize)>, usize, Vec<Label>);
 
 #[derive(Debug, Default)]
 pub struct DagGraph {
+    kind: GraphKind,
     data: Digraph,
     max_width: usize,
     max_height: usize,
     connections: Vec<Vec<Connection>>,
+    labels: Vec<Label>,
+    clusters: Vec<Cluster>,
     spacing: Vec<(isize, isize)>,
     line_height: Vec<usize>,
     attribs: Attrib,
 }
 
+impl DagGraph {
+    /// Directed graph that can have cycles, drawn with the edges that close them pointing up
+    pub fn digraph() -> Self {
+        Self {
+            kind: GraphKind::Digraph,
+            ..Default::default()
+        }
+    }
+
+    /// Undirected graph, drawn without arrowheads
+    pub fn graph() -> Self {
+        Self {
+            kind: GraphKind::Graph,
+            ..Default::default()
+        }
+    }
+
+    /// UML class diagram, laid out like a digraph with the heads of class relationships
+    pub(super) fn class() -> Self {
+        Self {
+            kind: GraphKind::Class,
+            ..Default::default()
+        }
+    }
+
+    /// Edge written with `op`, if this kind of graph has such edges
+    pub(super) fn edge(&self, op: &str) -> Option<EdgeData> {
+        self.kind.edge(op)
+    }
+
+    /// Lay out the nodes and edges of a script, which is shared by the graph diagrams
+    pub(super) fn lay_out(&mut self, script: GraphScript, attribs: Attrib) -> anyhow::Result<()> {
+        let GraphScript {
+            assigns: assign_map,
+            records: record_map,
+            shapes: shape_map,
+            clusters: cluster_map,
+            edges: relationship_map,
+            nodes,
+        } = script;
+        let mut dag = init_dag(&relationship_map, &nodes, self.kind == GraphKind::Graph);
+        self.clusters = Vec::new();
+        for (idx, (name, members)) in cluster_map.iter().enumerate() {
+            if dag.node_weights().any(|n| n.value == name.as_str()) {
+                return Err(ScriptError::new(
+                    ErrorKind::Diagram,
+                    format!("cluster has the same name as a node: {}", name.as_str()),
+                )
+                .with_span(*name)
+                .into());
+            }
+            for member in members {
+                let n = dag
+                    .node_indices()
+                    .find(|n| dag[*n].value == member.as_str())
+                    .ok_or_else(|| {
+                        ScriptError::new(
+                            ErrorKind::Diagram,
+                            format!("node in cluster has no edges: {}", member.as_str()),
+                        )
+                        .with_span(*member)
+                    })?;
+                if dag[n].cluster.is_some_and(|c| c != idx) {
+                    return Err(ScriptError::new(
+                        ErrorKind::Diagram,
+                        format!("node is in more than one cluster: {}", member.as_str()),
+                    )
+                    .with_span(*member)
+                    .into());
+                }
+                dag[n].cluster = Some(idx);
+            }
+            self.clusters.push(Cluster {
+                label: assign_map
+                    .get(name.as_str())
+                    .cloned()
+                    .unwrap_or_else(|| name.as_str().to_string()),
+                ..Default::default()
+            });
+        }
+        if self.kind != GraphKind::Dag {
+            // Edges from a node to itself are drawn as loops beside it, and take no part
+            // in the layout. Removing the last edges first keeps the others in place.
+            let loops: Vec<EdgeIndex> = dag
+                .edge_indices()
+                .filter(|e| {
+                    let (s, t) = dag.edge_endpoints(*e).unwrap();
+                    s == t
+                })
+                .collect();
+            for e in loops.into_iter().rev() {
+                let n = dag.edge_endpoints(e).unwrap().0;
+                dag[n].self_loop = dag.remove_edge(e);
+            }
+            dag = break_cycles(dag);
+        } else if is_cyclic_directed(&dag) {
+            return Err(
+                ScriptError::new(ErrorKind::Diagram, "directed acyclic graph has cycles").into(),
+            );
+        }
+        let (mut dag, max_level) = assign_level(dag);
+        dag = replace_text(
+            add_dummy(dag),
+            &assign_map,
+            &record_map,
+            &shape_map,
+            &attribs,
+        );
+        dag = hold_clusters(dag, &mut self.clusters);
+        let levels = level_cnt(&dag, max_level);
+        dag = permute(dag, &levels, &mut StdRng::seed_from_u64(attribs.seed));
+        let perm_levels = get_perm_levels(&dag, max_level);
+        let (_w, _, line_height) = place_node(
+            &mut dag,
+            &levels,
+            &perm_levels,
+            &self.clusters,
+            attribs.direction,
+        );
+        let width_shift;
+        (self.connections, self.spacing, width_shift, self.labels) = add_connections(
+            &mut dag,
+            &perm_levels,
+            &mut self.clusters,
+            attribs.direction,
+        );
+        self.max_width = self
+            .labels
+            .iter()
+            .map(|l| l.x + l.size.0)
+            .fold(_w + width_shift, max);
+        self.max_height = self
+            .spacing
+            .iter()
+            .map(|s| (s.1 - s.0) as usize)
+            .sum::<usize>()
+            + line_height.iter().sum::<usize>();
+        self.line_height = line_height;
+        self.data = dag;
+        self.attribs = attribs;
+        Ok(())
+    }
+}
+
+/// Nodes and edges written in a script, before they are laid out
+#[derive(Default)]
+pub(super) struct GraphScript<'a> {
+    pub(super) assigns: HashMap<&'a str, String>,
+    pub(super) records: HashMap<&'a str, Table<String>>,
+    pub(super) shapes: HashMap<&'a str, Shape>,
+    pub(super) clusters: Vec<(Span<'a>, Vec<Span<'a>>)>,
+    pub(super) edges: Vec<(&'a str, &'a str, EdgeData)>,
+    /// Nodes drawn even if no edge has them
+    pub(super) nodes: Vec<&'a str>,
+}
+
+/// The graph diagrams that share the layout of dag
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+enum GraphKind {
+    #[default]
+    Dag,
+    Digraph,
+    Graph,
+    Class,
+}
+
+impl GraphKind {
+    fn tag(&self) -> &'static str {
+        match self {
+            GraphKind::Dag => "dag",
+            GraphKind::Digraph => "digraph",
+            GraphKind::Graph => "graph",
+            GraphKind::Class => "class",
+        }
+    }
+
+    /// Edge written with `op`, if this kind of graph has such edges
+    fn edge(&self, op: &str) -> Option<EdgeData> {
+        // Class relationships have their head at the upper end, on the class they point to
+        let (arrows, line, head) = match (self, op) {
+            (GraphKind::Class, "<|--") => (Arrows::Up, Line::Solid, Head::Triangle),
+            (GraphKind::Class, "*--") => (Arrows::Up, Line::Solid, Head::Diamond),
+            (GraphKind::Class, "o--") => (Arrows::Up, Line::Solid, Head::HollowDiamond),
+            (GraphKind::Class, _) => (Arrows::Down, Line::Solid, Head::Arrow),
+            (_, "-->") => (Arrows::Down, Line::Dashed, Head::Arrow),
+            (_, "=>") => (Arrows::Down, Line::Bold, Head::Arrow),
+            (_, "<->") => (Arrows::Both, Line::Solid, Head::Arrow),
+            (_, "--") => (Arrows::Neither, Line::Solid, Head::Arrow),
+            _ => (Arrows::Down, Line::Solid, Head::Arrow),
+        };
+        if *self == GraphKind::Graph && arrows != Arrows::Neither {
+            return None;
+        }
+        Some(EdgeData {
+            arrows,
+            line,
+            head,
+            ..Default::default()
+        })
+    }
+}
+
 impl Diagram for DagGraph {
     fn parse_from_str(&mut self, input: &str, attribs: Attrib) -> anyhow::Result<()> {
-        let mut assign_map: HashMap<&str, &str> = HashMap::new();
-        let mut relationship_map: HashSet<(&str, &str)> = HashSet::new();
+        let mut assign_map: HashMap<&str, String> = HashMap::new();
+        let mut record_map: HashMap<&str, Table<String>> = HashMap::new();
+        let mut node_styles: HashMap<&str, NodeStyle> = HashMap::new();
+        let mut class_styles: HashMap<&str, NodeStyle> = HashMap::new();
+        let mut cluster_map: Vec<(Span, Vec<Span>)> = Vec::new();
+        let mut relationship_map: Vec<(&str, &str, EdgeData)> = Vec::new();
         let diagram = DagGraphParser::parse(Rule::diagram, input)
-            .map_err(|e| {
-                Error::msg(format!(
-                    "parsing error: incorrect dag grammar, context: {}",
-                    e.line()
-                ))
-            })?
+            .map_err(|e| ScriptError::from_pest(e, self.kind.tag()))?
             .next()
             .unwrap();
         for line in diagram.into_inner() {
@@ -50,168 +275,443 @@ impl Diagram for DagGraph {
                 Rule::assign => {
                     let mut statement = line.into_inner();
                     let variable = statement.next().unwrap().as_str();
-                    let value = statement.next().unwrap().as_str();
-                    assign_map.insert(variable, value);
+                    let value = statement.next().unwrap();
+                    if value.as_rule() == Rule::record {
+                        assign_map.remove(variable);
+                        record_map.insert(variable, record_table(variable, value));
+                    } else {
+                        record_map.remove(variable);
+                        assign_map.insert(variable, unescape(value.as_str()));
+                    }
+                    if let Some(attribs) = statement.next() {
+                        node_styles.entry(variable).or_default().update(attribs)?;
+                    }
+                }
+                Rule::node_style => {
+                    let mut statement = line.into_inner();
+                    let variable = statement.next().unwrap().as_str();
+                    let attribs = statement.next().unwrap();
+                    node_styles.entry(variable).or_default().update(attribs)?;
+                }
+                Rule::class_def => {
+                    let mut statement = line.into_inner();
+                    let name = statement.next().unwrap().as_str();
+                    let style = class_styles.entry(name).or_default();
+                    style.update(statement.next().unwrap())?;
+                    if let Some(class) = style.class {
+                        return Err(ScriptError::new(
+                            ErrorKind::Parsing,
+                            "a class cannot have a class of its own",
+                        )
+                        .with_span(class)
+                        .into());
+                    }
+                }
+                Rule::cluster => {
+                    let mut statement = line.into_inner().skip(1);
+                    let name = statement.next().unwrap().as_span();
+                    if cluster_map.iter().any(|(c, _)| c.as_str() == name.as_str()) {
+                        return Err(ScriptError::new(
+                            ErrorKind::Diagram,
+                            format!("cluster is defined more than once: {}", name.as_str()),
+                        )
+                        .with_span(name)
+                        .into());
+                    }
+                    cluster_map.push((name, statement.map(|m| m.as_span()).collect()));
                 }
                 Rule::relationship => {
                     let mut r = line.into_inner();
                     let mut from = r.next().unwrap();
-                    for to in r {
-                        relationship_map.insert((from.as_str(), to.as_str()));
+                    while let Some(op) = r.next() {
+                        if op.as_rule() == Rule::edge_label {
+                            relationship_map.last_mut().unwrap().2.label =
+                                Some(unescape(op.as_str()));
+                            break;
+                        }
+                        let edge = self.kind.edge(op.as_str()).ok_or_else(|| {
+                            ScriptError::new(
+                                ErrorKind::Parsing,
+                                "edges in graph have no direction, write them as a--b",
+                            )
+                            .with_span(op.as_span())
+                        })?;
+                        let to = r.next().unwrap();
+                        relationship_map.push((from.as_str(), to.as_str(), edge));
                         from = to;
                     }
                 }
                 _ => (),
             }
         }
-        let dag = init_dag(&relationship_map);
-        if is_cyclic_directed(&dag) {
-            return Err(Error::msg(
-                "diagram error: directed acyclic graph has cycles",
-            ));
+        // A shape given to the node itself wins over the one of its class
+        let mut shape_map: HashMap<&str, Shape> = HashMap::new();
+        for (variable, style) in node_styles.iter() {
+            let class_shape = match style.class {
+                Some(class) => {
+                    class_styles
+                        .get(class.as_str())
+                        .ok_or_else(|| {
+                            ScriptError::new(
+                                ErrorKind::Diagram,
+                                format!("class is not defined: {}", class.as_str()),
+                            )
+                            .with_span(class)
+                        })?
+                        .shape
+                }
+                None => None,
+            };
+            if let Some(shape) = style.shape.or(class_shape) {
+                shape_map.insert(variable, shape);
+            }
+        }
+        self.lay_out(
+            GraphScript {
+                assigns: assign_map,
+                records: record_map,
+                shapes: shape_map,
+                clusters: cluster_map,
+                edges: relationship_map,
+                ..Default::default()
+            },
+            attribs,
+        )
+    }
+
+    /// Unlike other diagrams, graphs are drawn in unicode unless asked otherwise
+    fn default_attribs(&self) -> Attrib {
+        Attrib {
+            style: Style::Unicode,
+            ..Default::default()
         }
-        let (mut dag, max_level) = assign_level(dag);
-        dag = replace_text(add_dummy(dag), &assign_map);
-        let levels = level_cnt(&dag, max_level);
-        dag = permute(dag, &levels);
-        let perm_levels = get_perm_levels(&dag, max_level);
-        let (_w, _, line_height) = place_node(&mut dag, &levels, &perm_levels);
-        let width_shift;
-        (self.connections, self.spacing, width_shift) = add_connections(&mut dag, &perm_levels);
-        self.max_width = _w + width_shift;
-        self.max_height = self
-            .spacing
-            .iter()
-            .map(|s| (s.1 - s.0) as usize)
-            .sum::<usize>()
-            + line_height.iter().sum::<usize>();
-        self.line_height = line_height;
-        self.data = dag;
-        self.attribs = attribs;
-        Ok(())
     }
 
     fn write(&self) -> anyhow::Result<Vec<u8>> {
+        let style = self.attribs.style;
+        let palette: Vec<char> = match (style, self.attribs.direction) {
+            (Style::Ascii, attrib::Direction::TopBottom) => PALETTE_ASCII,
+            (Style::Ascii, attrib::Direction::BottomTop) => PALETTE_ASCII_BT,
+            (Style::Ascii, attrib::Direction::LeftRight) => PALETTE_ASCII_LR,
+            (Style::Ascii, attrib::Direction::RightLeft) => PALETTE_ASCII_RL,
+            (Style::Unicode, attrib::Direction::TopBottom) => PALETTE_UNICODE,
+            (Style::Unicode, attrib::Direction::BottomTop) => PALETTE_UNICODE_BT,
+            (Style::Unicode, attrib::Direction::LeftRight) => PALETTE_UNICODE_LR,
+            (Style::Unicode, attrib::Direction::RightLeft) => PALETTE_UNICODE_RL,
+        }
+        .chars()
+        .collect();
+
         let mut buffer = vec![vec![' '; self.max_width]; self.max_height];
+        let mut canvas = Canvas::new(self.max_width, self.max_height);
+        // Cells just outside triangles, with the index of the tail they may take
+        let mut tails: Vec<(usize, usize, usize)> = Vec::new();
         for n in self.data.node_indices() {
             let (x, y) = self.data[n].pos;
             let (w, h) = (self.data[n].width, self.data[n].height);
 
-            // Draw corner
+            // Dummy nodes are part of an edge, and are drawn like its line. Those without
+            // edges only hold a place for a cluster.
             if self.data[n].dummy {
-                buffer[y][x] = PALETTE.chars().nth(9).unwrap();
-                buffer[y + h - 1][x] = PALETTE.chars().nth(9).unwrap();
-            } else {
-                buffer[y][x] = PALETTE.chars().nth(0).unwrap();
-                buffer[y][x + w - 1] = PALETTE.chars().nth(2).unwrap();
-                buffer[y + h - 1][x] = PALETTE.chars().nth(5).unwrap();
-                buffer[y + h - 1][x + w - 1] = PALETTE.chars().nth(7).unwrap();
+                if let Some(e) = self.data.edges(n).next() {
+                    for (row, cells) in buffer.iter_mut().enumerate().skip(y).take(h) {
+                        cells[x] = DUMMY;
+                        canvas.add(row, x, UP | DOWN, e.weight().line);
+                    }
+                }
+                continue;
             }
 
+            // Draw corner
+            buffer[y][x] = palette[0];
+            buffer[y][x + w - 1] = palette[2];
+            buffer[y + h - 1][x] = palette[5];
+            buffer[y + h - 1][x + w - 1] = palette[7];
+
             // Draw edge
-            for _x in (x + 1)..(x + w - 1) {
-                buffer[y][_x] = PALETTE.chars().nth(1).unwrap();
-                buffer[y + h - 1][_x] = PALETTE.chars().nth(6).unwrap();
-            }
+            let inner = (x + 1)..(x + w - 1);
+            buffer[y][inner.clone()].fill(palette[1]);
+            buffer[y + h - 1][inner].fill(palette[6]);
             for line in buffer.iter_mut().take(y + h - 1).skip(y + 1) {
-                line[x] = PALETTE.chars().nth(3).unwrap();
-                line[x + w - 1] = PALETTE.chars().nth(4).unwrap();
-            }
-            for (idx, c) in self.data[n].value.chars().enumerate() {
-                buffer[y + 1][x + 2 + idx] = c;
+                line[x] = palette[3];
+                line[x + w - 1] = palette[4];
             }
-        }
 
-        // Draw straight connections
-        for l in self.connections.iter() {
-            for con in l {
-                if let Connection::Straight { from, dummy } = con {
-                    let mut _y = from.1 + 1;
-                    while buffer[_y][from.0] == ' ' {
-                        buffer[_y][from.0] = PALETTE.chars().nth(9).unwrap();
-                        _y += 1;
-                    }
-                    if !dummy.0 {
-                        buffer[from.1][from.0] = PALETTE.chars().nth(8).unwrap();
-                    }
-                    if !dummy.1 {
-                        buffer[_y][from.0] = PALETTE.chars().nth(15).unwrap();
+            // A loop leaves the right side of the node and comes back a row below
+            if let Some(e) = &self.data[n].self_loop {
+                let pen = |i: usize| e.line.restyle(palette[i]);
+                let tail = loop_tail(e, self.attribs.direction);
+                buffer[y + 1][x + w - 1] = pen(if e.arrows.up() {
+                    e.head.side_index()
+                } else {
+                    16
+                });
+                buffer[y + 2][x + w - 1] = pen(if e.arrows.down() {
+                    e.head.side_index()
+                } else {
+                    16
+                });
+                for x in (x + w)..(x + w + tail) {
+                    canvas.add(y + 1, x, LEFT | RIGHT, e.line);
+                    canvas.add(y + 2, x, LEFT | RIGHT, e.line);
+                }
+                canvas.add(y + 1, x + w + tail, LEFT | DOWN, e.line);
+                canvas.add(y + 2, x + w + tail, LEFT | UP, e.line);
+                if e.head == Head::Triangle {
+                    for (row, end) in [(y + 1, e.arrows.up()), (y + 2, e.arrows.down())] {
+                        if end {
+                            tails.push((x + w, row, 20));
+                        }
                     }
                 }
             }
         }
 
-        // Draw other connections
+        // Lines run until they reach a node, and go on the canvas. Their ends are drawn on
+        // the edges of nodes, with the pen of their line.
         for (idx, l) in self.connections.iter().enumerate() {
             for con in l {
-                if let Connection::Bent { x1, x2, y, dummy } = con {
-                    let y0 = self.line_height.iter().take(idx + 1).sum::<usize>()
-                        + self
-                            .spacing
-                            .iter()
-                            .map(|s| (s.1 - s.0) as usize)
-                            .take(idx)
-                            .sum::<usize>();
-                    let _y = y0 + (y - self.spacing[idx].0) as usize;
-                    // Draw corner
-                    if x1 < x2 {
-                        buffer[_y][*x1] = PALETTE.chars().nth(11).unwrap();
-                        buffer[_y][*x2] = PALETTE.chars().nth(12).unwrap();
-                    } else {
-                        buffer[_y][*x1] = PALETTE.chars().nth(14).unwrap();
-                        buffer[_y][*x2] = PALETTE.chars().nth(13).unwrap();
-                    }
-                    // Draw first vertical
-                    let mut __y = _y - 1;
-                    while __y < self.max_height && *x1 < self.max_width && buffer[__y][*x1] == ' ' {
-                        buffer[__y][*x1] = PALETTE.chars().nth(9).unwrap();
-                        __y -= 1;
-                    }
-                    if !dummy.0 {
-                        buffer[__y][*x1] = PALETTE.chars().nth(8).unwrap();
+                match con {
+                    Connection::Straight {
+                        from,
+                        dummy,
+                        arrows,
+                        line,
+                        head,
+                        ..
+                    } => {
+                        let pen = |i: usize| line.restyle(palette[i]);
+                        let mut _y = from.1 + 1;
+                        while buffer[_y][from.0] == ' ' {
+                            canvas.add(_y, from.0, UP | DOWN, *line);
+                            _y += 1;
+                        }
+                        if !dummy.0 {
+                            buffer[from.1][from.0] =
+                                pen(if arrows.up() { head.index(true) } else { 8 });
+                            if arrows.up() && *head == Head::Triangle {
+                                tails.push((from.0, from.1 + 1, 19));
+                            }
+                        }
+                        if !dummy.1 {
+                            buffer[_y][from.0] =
+                                pen(if arrows.down() { head.index(false) } else { 11 });
+                            if arrows.down() && *head == Head::Triangle {
+                                tails.push((from.0, _y - 1, 19));
+                            }
+                        }
                     }
+                    Connection::Bent {
+                        x1,
+                        x2,
+                        y,
+                        dummy,
+                        arrows,
+                        line,
+                        head,
+                        ..
+                    } => {
+                        let pen = |i: usize| line.restyle(palette[i]);
+                        let _y = self.gap_row(idx, *y);
+                        let (toward, back) = if x1 < x2 {
+                            (RIGHT, LEFT)
+                        } else {
+                            (LEFT, RIGHT)
+                        };
+                        canvas.add(_y, *x1, UP | toward, *line);
+                        canvas.add(_y, *x2, DOWN | back, *line);
+                        for x in (*min(x1, x2) + 1)..*max(x1, x2) {
+                            canvas.add(_y, x, LEFT | RIGHT, *line);
+                        }
 
-                    // Draw second vertical
-                    __y = _y + 1;
-                    while __y < self.max_height && *x2 < self.max_width && buffer[__y][*x2] == ' ' {
-                        buffer[__y][*x2] = PALETTE.chars().nth(9).unwrap();
-                        __y += 1;
-                    }
-                    if !dummy.1 {
-                        buffer[__y][*x2] = PALETTE.chars().nth(15).unwrap();
+                        // Draw first vertical
+                        let mut __y = _y - 1;
+                        while buffer[__y][*x1] == ' ' {
+                            canvas.add(__y, *x1, UP | DOWN, *line);
+                            __y -= 1;
+                        }
+                        if !dummy.0 {
+                            buffer[__y][*x1] = pen(if arrows.up() { head.index(true) } else { 8 });
+                            if arrows.up() && *head == Head::Triangle {
+                                tails.push((*x1, __y + 1, 19));
+                            }
+                        }
+
+                        // Draw second vertical
+                        __y = _y + 1;
+                        while buffer[__y][*x2] == ' ' {
+                            canvas.add(__y, *x2, UP | DOWN, *line);
+                            __y += 1;
+                        }
+                        if !dummy.1 {
+                            buffer[__y][*x2] =
+                                pen(if arrows.down() { head.index(false) } else { 11 });
+                            if arrows.down() && *head == Head::Triangle {
+                                tails.push((*x2, __y - 1, 19));
+                            }
+                        }
                     }
                 }
             }
         }
 
-        // Draw horizontal
-        for (idx, l) in self.connections.iter().enumerate() {
-            for con in l {
-                if let Connection::Bent { x1, x2, y, .. } = con {
-                    let y0 = self.line_height.iter().take(idx + 1).sum::<usize>()
-                        + self
-                            .spacing
-                            .iter()
-                            .map(|s| (s.1 - s.0) as usize)
-                            .take(idx)
-                            .sum::<usize>();
-                    let _y = y0 + (y - self.spacing[idx].0) as usize;
-                    // println!("y: {}, x1: {}, x2: {}", _y, x1, x2);
-                    // Draw horizontal
-                    let (_x1, _x2) = (min(x1, x2), max(x1, x2));
-                    for h in *_x1 + 1..*_x2 {
-                        if _y < buffer.len() && h < buffer[0].len() && buffer[_y][h] == ' ' {
-                            buffer[_y][h] = PALETTE.chars().nth(10).unwrap();
-                        }
-                    }
+        let mut buffer = orient(buffer, self.attribs.direction);
+        let mut canvas = canvas.orient(self.attribs.direction);
+
+        // Nodes are drawn as boxes, and get their own shape once they are the right way round
+        let plain = Shape::Box.palette(style);
+        for n in self.data.node_indices() {
+            if self.data[n].dummy || self.data[n].shape == Shape::Box {
+                continue;
+            }
+            let shape = self.data[n].shape.palette(style);
+            let (top, left, height, width) =
+                self.orient_rect(self.data[n].pos, (self.data[n].width, self.data[n].height));
+            let (bottom, right) = (top + height - 1, left + width - 1);
+            let corners = [(top, left), (top, right), (bottom, left), (bottom, right)];
+            for (i, (y, x)) in corners.into_iter().enumerate() {
+                if buffer[y][x] == plain[i] {
+                    buffer[y][x] = shape[i];
+                }
+            }
+            // Junctions are kept where connections meet the edge, arrowheads are left alone
+            let mut reshape_edge = |y: usize, x: usize, side: usize| {
+                if buffer[y][x] == plain[4 + side] {
+                    buffer[y][x] = shape[4 + side];
+                } else if buffer[y][x] == plain[8 + side] {
+                    buffer[y][x] = shape[8 + side];
+                }
+            };
+            for x in (left + 1)..right {
+                reshape_edge(top, x, 0);
+                reshape_edge(bottom, x, 1);
+            }
+            // A diamond comes to a point in the middle of its sides, however tall it is
+            for y in (top + 1)..bottom {
+                if self.data[n].shape == Shape::Diamond && y != top + height / 2 {
+                    continue;
+                }
+                reshape_edge(y, left, 2);
+                reshape_edge(y, right, 3);
+            }
+        }
+
+        // Cluster edges are lines too, which edges cross
+        let titles: Vec<String> = self
+            .clusters
+            .iter()
+            .map(|c| format!(" {} ", c.label))
+            .collect();
+        let mut title_cells = Vec::new();
+        for (idx, c) in self.clusters.iter().enumerate() {
+            let (left, right) = cluster_bounds(&self.data, idx, c, self.attribs.direction);
+            let top = self.level_row(c.levels.0);
+            let bottom = self.level_row(c.levels.1) + self.line_height[c.levels.1 - 1] - 1;
+            let (top, left, height, width) =
+                self.orient_rect((left, top), (right - left + 1, bottom - top + 1));
+            let (bottom, right) = (top + height - 1, left + width - 1);
+            canvas.add(top, left, DOWN | RIGHT, Line::Solid);
+            canvas.add(top, right, DOWN | LEFT, Line::Solid);
+            canvas.add(bottom, left, UP | RIGHT, Line::Solid);
+            canvas.add(bottom, right, UP | LEFT, Line::Solid);
+            for x in (left + 1)..right {
+                canvas.add(top, x, LEFT | RIGHT, Line::Solid);
+                canvas.add(bottom, x, LEFT | RIGHT, Line::Solid);
+            }
+            for y in (top + 1)..bottom {
+                canvas.add(y, left, UP | DOWN, Line::Solid);
+                canvas.add(y, right, UP | DOWN, Line::Solid);
+            }
+            title_cells.push((top, left + 2));
+        }
+
+        // A tail goes on a straight line only, so it never hides a turn or a crossing
+        for (x, y, i) in tails {
+            let (row, col, _, _) = self.orient_rect((x, y), (1, 1));
+            if palette[i] != ' ' && buffer[row][col] == ' ' && canvas.0[row][col].0 == LEFT | RIGHT
+            {
+                buffer[row][col] = palette[i];
+            }
+        }
+
+        for (y, row) in buffer.iter_mut().enumerate() {
+            for (x, c) in row.iter_mut().enumerate() {
+                if *c == ' ' || *c == DUMMY {
+                    *c = canvas.glyph(y, x, style);
                 }
             }
         }
 
+        // Node text is written as a whole at its position, over cells marked with '\0'
+        let mut texts: HashMap<(usize, usize), Cow<str>> = HashMap::new();
+        for ((row, col), title) in title_cells.into_iter().zip(titles.iter()) {
+            buffer[row][col..(col + display_width(title))].fill('\0');
+            texts.insert((row, col), title.into());
+        }
+        for n in self.data.node_indices().filter(|n| !self.data[*n].dummy) {
+            let (row, col, height, width) =
+                self.orient_rect(self.data[n].pos, (self.data[n].width, self.data[n].height));
+            if let Some(record) = &self.data[n].record {
+                self.write_record(
+                    &mut buffer,
+                    &mut texts,
+                    record,
+                    n,
+                    (row, col, height, width),
+                );
+                continue;
+            }
+            // Lines are centered between the top and bottom edges
+            let lines: Vec<&str> = self.data[n].value.split('\n').collect();
+            let top = row + 1 + (height - 2 - lines.len()) / 2;
+            for (i, line) in lines.into_iter().enumerate() {
+                buffer[top + i][(col + 2)..(col + 2 + display_width(line))].fill('\0');
+                texts.insert((top + i, col + 2), line.into());
+            }
+        }
+        for l in self.labels.iter() {
+            let (row, col, _, _) = self.orient_rect((l.x, self.gap_row(l.gap, l.y)), l.size);
+            buffer[row][col..(col + display_width(&l.text))].fill('\0');
+            texts.insert((row, col), l.text.as_str().into());
+        }
+        // Labels of loops go after the loop, which is below it once levels are side by side
+        for n in self.data.node_indices() {
+            let Some(text) = self.data[n]
+                .self_loop
+                .as_ref()
+                .and_then(|e| e.label.as_ref())
+            else {
+                continue;
+            };
+            let (x, y) = self.data[n].pos;
+            let len = display_width(text);
+            let (pos, size) = if self.attribs.direction.is_horizontal() {
+                ((x + self.data[n].width + 1, y + 1), (1, len))
+            } else {
+                let tail = self.data[n]
+                    .self_loop
+                    .as_ref()
+                    .map_or(0, |e| loop_tail(e, self.attribs.direction));
+                ((x + self.data[n].width + tail + 2, y + 1), (len, 1))
+            };
+            let (row, col, _, _) = self.orient_rect(pos, size);
+            buffer[row][col..(col + len)].fill('\0');
+            texts.insert((row, col), text.into());
+        }
+
+        // Side by side levels leave the spare width as blank rows at the bottom
+        let used_rows = buffer
+            .iter()
+            .rposition(|row| row.iter().any(|c| *c != ' '))
+            .map_or(0, |y| y + 1);
         let mut res: Vec<u8> = Vec::new();
-        for row in buffer.iter() {
-            for c in row.iter() {
-                res.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+        for (y, row) in buffer.iter().take(used_rows).enumerate() {
+            for (x, c) in row.iter().enumerate() {
+                if let Some(text) = texts.get(&(y, x)) {
+                    res.extend_from_slice(text.as_bytes());
+                } else if *c != '\0' {
+                    res.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
             }
             res.push(b'\n');
         }
@@ -219,6 +719,113 @@ impl Diagram for DagGraph {
     }
 }
 
+impl DagGraph {
+    /// Write the compartments of a record node as the rows of a table, with its name
+    /// centered in the first, and join the lines between them to the edges of the node
+    ///
+    /// Side by side levels start the compartments at the top, which keeps the lines
+    /// between them where the node has no connections.
+    fn write_record(
+        &self,
+        buffer: &mut [Vec<char>],
+        texts: &mut HashMap<(usize, usize), Cow<str>>,
+        record: &Table<String>,
+        n: NodeIndex,
+        (row, col, height, width): (usize, usize, usize, usize),
+    ) {
+        let style = self.attribs.style;
+        let shape = self.data[n].shape.palette(style);
+        let table = draw_table(record, &[width - 4], style, true);
+        let mut lines = table[1..(table.len() - 1)].iter();
+        let right = col + width - 1;
+        let mut y = if self.attribs.direction.is_horizontal() {
+            row + 1
+        } else {
+            row + 1 + (height - 2 - lines.len()) / 2
+        };
+        for (i, rows) in record.row_height().into_iter().enumerate() {
+            if i > 0 {
+                // Connections may already meet the edge here, and keep their junction
+                if buffer[y][col] == shape[6] {
+                    buffer[y][col] = shape[11];
+                }
+                if buffer[y][right] == shape[7] {
+                    buffer[y][right] = shape[10];
+                }
+                let line = lines.next().unwrap();
+                for (cell, c) in buffer[y][(col + 1)..right]
+                    .iter_mut()
+                    .zip(line.chars().skip(1))
+                {
+                    *cell = c;
+                }
+                y += 1;
+            }
+            for line in lines.by_ref().take(rows) {
+                // The text between the borders of the table, which are a column each
+                let mut inner = line.chars();
+                inner.next();
+                inner.next_back();
+                buffer[y][(col + 1)..right].fill('\0');
+                texts.insert((y, col + 1), inner.as_str().to_string().into());
+                y += 1;
+            }
+        }
+    }
+
+    /// Row of the buffer for row `y` of the gap below level `gap`
+    fn gap_row(&self, gap: usize, y: isize) -> usize {
+        self.line_height.iter().take(gap + 1).sum::<usize>()
+            + self
+                .spacing
+                .iter()
+                .map(|s| (s.1 - s.0) as usize)
+                .take(gap)
+                .sum::<usize>()
+            + (y - self.spacing[gap].0) as usize
+    }
+
+    /// Row of the buffer where level `level` starts
+    fn level_row(&self, level: usize) -> usize {
+        self.line_height.iter().take(level - 1).sum::<usize>()
+            + self
+                .spacing
+                .iter()
+                .map(|s| (s.1 - s.0) as usize)
+                .take(level - 1)
+                .sum::<usize>()
+    }
+
+    /// Top row, left column, height and width of a rectangle after orienting the buffer
+    fn orient_rect(
+        &self,
+        (x, y): (usize, usize),
+        (w, h): (usize, usize),
+    ) -> (usize, usize, usize, usize) {
+        match self.attribs.direction {
+            attrib::Direction::TopBottom => (y, x, h, w),
+            attrib::Direction::BottomTop => (self.max_height - y - h, x, h, w),
+            attrib::Direction::LeftRight => (x, y, w, h),
+            attrib::Direction::RightLeft => (x, self.max_height - y - h, w, h),
+        }
+    }
+}
+
+/// Turn a buffer laid out top to bottom into the given direction
+fn orient<T: Copy>(buffer: Vec<Vec<T>>, direction: attrib::Direction) -> Vec<Vec<T>> {
+    let (height, width) = (buffer.len(), buffer.first().map_or(0, |r| r.len()));
+    match direction {
+        attrib::Direction::TopBottom => buffer,
+        attrib::Direction::BottomTop => buffer.into_iter().rev().collect(),
+        attrib::Direction::LeftRight => (0..width)
+            .map(|x| (0..height).map(|y| buffer[y][x]).collect())
+            .collect(),
+        attrib::Direction::RightLeft => (0..width)
+            .map(|x| (0..height).rev().map(|y| buffer[y][x]).collect())
+            .collect(),
+    }
+}
+
 fn get_perm_levels(g: &Digraph, max_level: usize) -> Vec<Vec<NodeIndex>> {
     let mut res = vec![vec![]; max_level];
     for n in g.node_indices() {
@@ -226,7 +833,7 @@ fn get_perm_levels(g: &Digraph, max_level: usize) -> Vec<Vec<NodeIndex>> {
     }
     res.into_iter()
         .map(|mut r| {
-            r.sort_by(|a, b| a.1.cmp(&b.1));
+            r.sort_by_key(|a| a.1);
             r.into_iter().map(|(a, _)| a).collect()
         })
         .collect()
@@ -235,7 +842,9 @@ fn get_perm_levels(g: &Digraph, max_level: usize) -> Vec<Vec<NodeIndex>> {
 fn place_node(
     g: &mut Digraph,
     levels: &[usize],
-    perm_levels: &Vec<Vec<NodeIndex>>,
+    perm_levels: &[Vec<NodeIndex>],
+    clusters: &[Cluster],
+    direction: attrib::Direction,
 ) -> (usize, usize, Vec<usize>) {
     fn shift_right(
         g: &mut Digraph,
@@ -253,21 +862,46 @@ fn place_node(
     }
 
     let mut cur_row = 0;
-    let mut max_col = 0;
 
-    for r in perm_levels {
+    // Levels where clusters start or end have a row for their edges above or below the nodes
+    let pads: Vec<(usize, usize)> = (1..=perm_levels.len())
+        .map(|l| {
+            (
+                clusters.iter().any(|c| c.levels.0 == l) as usize,
+                clusters.iter().any(|c| c.levels.1 == l) as usize,
+            )
+        })
+        .collect();
+    let mut row_height: Vec<usize> = perm_levels
+        .iter()
+        .zip(pads.iter())
+        .map(|(r, p)| p.0 + r.iter().map(|c| g[*c].height).max().unwrap_or(0) + p.1)
+        .collect();
+    // Side by side levels run the title of a cluster across its level, which leaves room
+    // for the corners and a line on either side of the title with its spaces
+    if direction.is_horizontal() {
+        for c in clusters {
+            let l = c.title_level(direction);
+            row_height[l - 1] = max(row_height[l - 1], display_width(&c.label) + 6);
+        }
+    }
+
+    // Nodes are aligned to the top of their level, and dummy nodes run through all of it
+    for ((r, h), p) in perm_levels.iter().zip(row_height.iter()).zip(pads.iter()) {
         let mut cur_col = 0;
         for c in r {
-            g[*c].pos = (cur_col, cur_row);
-            cur_col += g[*c].width + 1;
+            if g[*c].dummy {
+                g[*c].pos = (cur_col, cur_row);
+                g[*c].height = *h;
+            } else {
+                g[*c].pos = (cur_col, cur_row + p.0);
+            }
+            cur_col += g[*c].width + g[*c].loop_width + 1;
         }
-        max_col = max(cur_col, max_col);
-        cur_row += 3;
+        cur_row += h;
     }
-    let row_height: Vec<usize> = vec![3; levels.len()];
 
     let cl = cal_crossings_levels(g, levels.len());
-    let mut max_shift_right = 0;
 
     const MAX_LOOP: usize = 3;
     let mut loop_cnt = 0;
@@ -298,7 +932,6 @@ fn place_node(
                     } else {
                         g[*n].width += shift as usize;
                     }
-                    max_shift_right = max(max_shift_right, shift as usize);
                     shift_right(g, perm_levels, idx1 + 2, idx2 + 1, shift as usize);
                 }
             }
@@ -330,7 +963,6 @@ fn place_node(
                     } else {
                         g[*n].width += shift as usize;
                     }
-                    max_shift_right = max(max_shift_right, shift as usize);
                     shift_right(g, perm_levels, idx1 + 1, idx2 + 1, shift as usize);
                 }
             }
@@ -338,13 +970,97 @@ fn place_node(
         loop_cnt += 1;
     }
 
-    (max_col + max_shift_right, cur_row, row_height)
+    // Every cluster has room for its left edge and title before its nodes, so its bounds
+    // can be measured before it is made way for
+    for (idx, c) in clusters.iter().enumerate() {
+        let margin = (c.levels.0..=c.levels.1)
+            .map(|l| c.margin(l, direction))
+            .max()
+            .unwrap();
+        for l in c.levels.0..=c.levels.1 {
+            let r = &perm_levels[l - 1];
+            let first = r.iter().position(|n| g[*n].cluster == Some(idx)).unwrap();
+            let x = g[r[first]].pos.0;
+            if x < margin {
+                shift_right(g, perm_levels, l, first, margin - x);
+            }
+        }
+    }
+
+    // Nodes next to a cluster make way for its edges. Clusters are in the same order in
+    // every level, so making way for one never has to be undone for another.
+    for _ in 0..=clusters.len() {
+        let mut moved = false;
+        for (idx, c) in clusters.iter().enumerate() {
+            let block = |g: &Digraph, l: usize| {
+                let r = &perm_levels[l - 1];
+                let first = r.iter().position(|n| g[*n].cluster == Some(idx)).unwrap();
+                let last = r.iter().rposition(|n| g[*n].cluster == Some(idx)).unwrap();
+                (first, last)
+            };
+            let left = (c.levels.0..=c.levels.1)
+                .filter_map(|l| {
+                    let first = block(g, l).0;
+                    let prev = &g[*perm_levels[l - 1].get(first.checked_sub(1)?)?];
+                    Some(match prev.cluster {
+                        Some(k) => cluster_bounds(g, k, &clusters[k], direction).1 + 2,
+                        None => prev.pos.0 + prev.width + prev.loop_width + 1,
+                    })
+                })
+                .max()
+                .unwrap_or(0);
+            // Nodes in all levels start past the title, which keeps edges inside straight
+            let need = left
+                + (c.levels.0..=c.levels.1)
+                    .map(|l| c.margin(l, direction))
+                    .max()
+                    .unwrap();
+            for l in c.levels.0..=c.levels.1 {
+                let first = block(g, l).0;
+                let x = g[perm_levels[l - 1][first]].pos.0;
+                if x < need {
+                    shift_right(g, perm_levels, l, first, need - x);
+                    moved = true;
+                }
+            }
+            let right = cluster_bounds(g, idx, c, direction).1;
+            for l in c.levels.0..=c.levels.1 {
+                let last = block(g, l).1;
+                if let Some(next) = perm_levels[l - 1].get(last + 1) {
+                    let x = g[*next].pos.0;
+                    if x < right + 2 {
+                        shift_right(g, perm_levels, l, last + 1, right + 2 - x);
+                        moved = true;
+                    }
+                }
+            }
+        }
+        if !moved {
+            break;
+        }
+    }
+
+    // Shifts add up along a level, so measure the width from where the nodes ended up
+    let width = g
+        .node_weights()
+        .map(|n| n.pos.0 + n.width + n.loop_width + 1)
+        .chain(
+            clusters
+                .iter()
+                .enumerate()
+                .map(|(idx, c)| cluster_bounds(g, idx, c, direction).1 + 2),
+        )
+        .max()
+        .unwrap_or(0);
+    (width, cur_row, row_height)
 }
 
 fn add_connections(
     g: &mut Digraph,
     perm_levels: &[Vec<NodeIndex>],
-) -> (Vec<Vec<Connection>>, Vec<(isize, isize)>, usize) {
+    clusters: &mut [Cluster],
+    direction: attrib::Direction,
+) -> Routes {
     fn shift_down(g: &mut Digraph, perm_levels: &[Vec<NodeIndex>], level: usize, amount: usize) {
         for r in &perm_levels[level - 1..] {
             for c in r {
@@ -380,35 +1096,37 @@ fn add_connections(
             }
         }
     }
+    /// First and last column a straight line between two nodes can run down
     fn overlap(
         (x1, w1, dummy1): (usize, usize, bool),
         (x2, w2, dummy2): (usize, usize, bool),
-    ) -> Option<usize> {
+    ) -> Option<(usize, usize)> {
         if dummy1 && dummy2 {
             if x1 == x2 {
-                Some(x1)
+                Some((x1, x1))
             } else {
                 None
             }
         } else if dummy1 {
             if x1 > x2 && x1 < x2 + w2 - 1 {
-                Some(x1)
+                Some((x1, x1))
             } else {
                 None
             }
         } else if dummy2 {
             if x2 > x1 && x2 < x1 + w1 - 1 {
-                Some(x2)
+                Some((x2, x2))
             } else {
                 None
             }
         } else if x1 + w1 - 1 < x2 + 2 || x2 + w2 - 1 < x1 + 2 {
             None
         } else {
-            Some(max(x1, x2) + 1)
+            Some((max(x1, x2) + 1, min(x1 + w1, x2 + w2) - 2))
         }
     }
 
+    let horizontal = direction.is_horizontal();
     let mut ct: Vec<Vec<Connection>> = vec![vec![]; perm_levels.len() - 1];
 
     fn connections_contain_x(c: &[Vec<Connection>], i: usize, x: usize, d: Direction) -> bool {
@@ -478,21 +1196,38 @@ fn add_connections(
         res
     }
 
-    // Make straight connections
-    for e in g.edge_indices() {
+    // Make straight connections. Edges between the same nodes run side by side, with
+    // reversed edges to the right of the others.
+    let mut edges: Vec<EdgeIndex> = g.edge_indices().collect();
+    edges.sort_by_key(|e| g[*e].reversed);
+    for e in edges {
         let (s, t) = g.edge_endpoints(e).unwrap();
-        if let Some(x) = overlap(
+        let Some((first, last)) = overlap(
             (g[s].pos.0, g[s].width, g[s].dummy),
             (g[t].pos.0, g[t].width, g[t].dummy),
-        ) {
-            ct[g[s].level - 1].push(Connection::Straight {
+        ) else {
+            continue;
+        };
+        let level = g[s].level - 1;
+        if let Some(x) = (first..=last).find(|x| {
+            !connections_contain_x(&ct, level, *x, Outgoing)
+                && !g[s].separators.contains(&(x - g[s].pos.0))
+                && !g[t].separators.contains(&(x - g[t].pos.0))
+        }) {
+            ct[level].push(Connection::Straight {
                 from: (x, g[s].pos.1 + g[s].height - 1),
                 dummy: (g[s].dummy, g[t].dummy),
+                arrows: g[e].arrows,
+                line: g[e].line,
+                head: g[e].head,
+                label: g[e].label.clone(),
             });
             g[e].visited = true;
         }
     }
 
+    // Leave room for arrows between levels side by side
+    let min_spacing = if horizontal { 2 } else { 0 };
     let mut spacing: Vec<(isize, isize)> = vec![(0, 0); perm_levels.len() - 1];
     let mut shift_right = 0;
     let mut res = g.clone();
@@ -515,17 +1250,24 @@ fn add_connections(
                 out_offset = if res[c].dummy {
                     0
                 } else {
-                    while connections_contain_x(&ct, idx, res[c].pos.0 + out_offset, Outgoing) {
+                    while connections_contain_x(&ct, idx, res[c].pos.0 + out_offset, Outgoing)
+                        || res[c].separators.contains(&out_offset)
+                    {
                         out_offset += 1;
                     }
                     out_offset
                 };
                 assert!(out_offset <= res[c].width);
+                // A bent line needs its ends in different columns, which only edges
+                // between the same nodes could otherwise give it
                 let in_offset = if res[t].dummy {
                     0
                 } else {
                     let mut _in_offset = 1;
-                    while connections_contain_x(&ct, idx, res[t].pos.0 + _in_offset, Incoming) {
+                    while connections_contain_x(&ct, idx, res[t].pos.0 + _in_offset, Incoming)
+                        || res[t].pos.0 + _in_offset == res[c].pos.0 + out_offset
+                        || res[t].separators.contains(&_in_offset)
+                    {
                         _in_offset += 1;
                     }
                     _in_offset
@@ -547,6 +1289,10 @@ fn add_connections(
                             x2: x_to,
                             y: s,
                             dummy: (res[c].dummy, res[t].dummy),
+                            arrows: child_edge.weight().arrows,
+                            line: child_edge.weight().line,
+                            head: child_edge.weight().head,
+                            label: child_edge.weight().label.clone(),
                         });
                         found = true;
                         break;
@@ -565,14 +1311,48 @@ fn add_connections(
 
                     // Check vertical overlap
                     let vert_overlap_res = vertical_overlap(&ct, idx, y, x_from, x_to);
+                    // A node widens to make room, but a dummy can't, so it moves over
+                    // instead, leaving behind the line it was in the way of
                     if vert_overlap_res.0 {
-                        shift_right_one(&mut res, &mut ct, x_from + 1);
+                        if res[c].dummy {
+                            shift_right_one(&mut res, &mut ct, x_from);
+                            for con in ct[idx].iter_mut() {
+                                if let Connection::Bent {
+                                    x2,
+                                    dummy: (_, false),
+                                    ..
+                                } = con
+                                {
+                                    if *x2 == x_from + 1 {
+                                        *x2 = x_from;
+                                    }
+                                }
+                            }
+                        } else {
+                            shift_right_one(&mut res, &mut ct, x_from + 1);
+                        }
                         x_from += 1;
                         shift_right += 1;
                     }
 
                     if vert_overlap_res.1 {
-                        shift_right_one(&mut res, &mut ct, x_to + 1);
+                        if res[t].dummy {
+                            shift_right_one(&mut res, &mut ct, x_to);
+                            for con in ct[idx].iter_mut() {
+                                if let Connection::Bent {
+                                    x1,
+                                    dummy: (false, _),
+                                    ..
+                                } = con
+                                {
+                                    if *x1 == x_to + 1 {
+                                        *x1 = x_to;
+                                    }
+                                }
+                            }
+                        } else {
+                            shift_right_one(&mut res, &mut ct, x_to + 1);
+                        }
                         x_to += 1;
                         shift_right += 1;
                     }
@@ -581,12 +1361,89 @@ fn add_connections(
                         x2: x_to,
                         y,
                         dummy: (res[c].dummy, res[t].dummy),
+                        arrows: child_edge.weight().arrows,
+                        line: child_edge.weight().line,
+                        head: child_edge.weight().head,
+                        label: child_edge.weight().label.clone(),
                     });
                 }
             }
         }
     }
 
+    // Labels get their own space at the top of the gap, next to where their edge leaves.
+    // Across levels they run along a row, side by side they run down a column. Columns
+    // are put in when another edge, or a cluster the gap is in, is in the way.
+    let label_len = |con: &Connection| con.label().map(display_width);
+    for idx in 0..ct.len() {
+        let Some(max_len) = ct[idx].iter().filter_map(label_len).max() else {
+            continue;
+        };
+        spacing[idx].0 -= if horizontal { max_len as isize } else { 1 };
+        let mut labeled: Vec<usize> = (0..ct[idx].len())
+            .filter(|i| ct[idx][*i].label().is_some())
+            .collect();
+        labeled.sort_by_key(|i| ct[idx][*i].start_x());
+        for i in labeled {
+            let x = ct[idx][i].start_x();
+            let clear_to = if horizontal {
+                x + 1
+            } else {
+                x + label_len(&ct[idx][i]).unwrap() + 2
+            };
+            if let Some(blocking) = ct[idx]
+                .iter()
+                .map(|con| con.start_x())
+                .filter(|v| (x + 1..=clear_to).contains(v))
+                .min()
+            {
+                for _ in blocking..=clear_to {
+                    shift_right_one(&mut res, &mut ct, x + 1);
+                    shift_right += 1;
+                }
+            }
+            // Labels of edges that leave a cluster widen it instead
+            for (k, c) in clusters.iter_mut().enumerate() {
+                if c.levels.0 > idx + 1 || c.levels.1 < idx + 2 {
+                    continue;
+                }
+                let (left, right) = cluster_bounds(&res, k, c, direction);
+                if (x + 1..=clear_to).contains(&left) {
+                    for _ in left..=clear_to {
+                        shift_right_one(&mut res, &mut ct, left);
+                        shift_right += 1;
+                    }
+                } else if (x + 1..=clear_to).contains(&right) && x > left {
+                    for _ in right..=clear_to {
+                        shift_right_one(&mut res, &mut ct, right);
+                        shift_right += 1;
+                        c.room += 1;
+                    }
+                }
+            }
+        }
+    }
+    let mut labels: Vec<Label> = Vec::new();
+    for (idx, r) in ct.iter().enumerate() {
+        for con in r {
+            if let Some(text) = con.label() {
+                let len = display_width(text);
+                labels.push(Label {
+                    gap: idx,
+                    y: spacing[idx].0,
+                    x: con.start_x() + if horizontal { 1 } else { 2 },
+                    size: if horizontal { (1, len) } else { (len, 1) },
+                    text: text.to_string(),
+                });
+            }
+        }
+    }
+    for (s, r) in spacing.iter_mut().zip(ct.iter()) {
+        // Dashed and bold lines need a row to be seen, even if they run straight
+        let styled = r.iter().any(|con| con.line() != Line::Solid) as isize;
+        s.1 = max(s.1, s.0 + max(min_spacing, styled));
+    }
+
     // Shift things down
     for (idx, shift) in spacing.iter().enumerate() {
         shift_down(&mut res, perm_levels, idx + 2, (shift.1 - shift.0) as usize);
@@ -600,13 +1457,38 @@ fn add_connections(
     }
     *g = res;
 
-    (ct, spacing, shift_right)
+    (ct, spacing, shift_right, labels)
+}
+
+/// Table of a record node with a row for each compartment, the first of which is its name
+fn record_table(name: &str, record: Pair<Rule>) -> Table<String> {
+    let mut compartments = vec![name.to_string(), String::new()];
+    for p in record.into_inner() {
+        let last = compartments.last_mut().unwrap();
+        match (p.as_rule(), p.as_str()) {
+            (Rule::record_sep, "||") => compartments.push(String::new()),
+            (Rule::record_sep, _) => last.push('\n'),
+            _ => last.push_str(&unescape(p.as_str())),
+        }
+    }
+    Table {
+        height: compartments.len(),
+        width: 1,
+        cells: compartments
+            .into_iter()
+            .map(|value| vec![TableCell { value }])
+            .collect(),
+    }
 }
 
-fn init_dag(r: &HashSet<(&str, &str)>) -> Digraph {
-    let mut g = DiGraph::new();
+/// Build the graph, adding nodes in the order they first appear in the script
+///
+/// Undirected edges start out pointing the way they are written. Nodes without edges
+/// are added after the others.
+fn init_dag(r: &[(&str, &str, EdgeData)], nodes: &[&str], undirected: bool) -> Digraph {
+    let mut g = Digraph::new();
     let mut added: HashMap<&str, usize> = HashMap::new();
-    for (s, t) in r.iter() {
+    for (s, t, edge) in r.iter() {
         if !added.contains_key(*s) {
             let i = g.add_node(NodeData {
                 value: s.to_string(),
@@ -621,22 +1503,111 @@ fn init_dag(r: &HashSet<(&str, &str)>) -> Digraph {
             });
             added.insert(*t, j.index());
         }
-        g.update_edge(
-            NodeIndex::new(added[*s]),
-            NodeIndex::new(added[*t]),
-            EdgeData::default(),
-        );
+        let (s, t) = (NodeIndex::new(added[*s]), NodeIndex::new(added[*t]));
+        // An edge written again without a label keeps the label it already has, while
+        // class relationships with another head are edges of their own
+        let same_edge = if undirected {
+            g.find_edge_undirected(s, t).map(|(e, _)| e)
+        } else {
+            g.edges_connecting(s, t)
+                .find(|e| e.weight().head == edge.head)
+                .map(|e| e.id())
+        };
+        match same_edge {
+            Some(e) => {
+                if edge.label.is_some() {
+                    g[e].label.clone_from(&edge.label);
+                }
+            }
+            None => {
+                g.add_edge(s, t, edge.clone());
+            }
+        }
+    }
+    for n in nodes {
+        if !added.contains_key(n) {
+            let i = g.add_node(NodeData {
+                value: n.to_string(),
+                ..Default::default()
+            });
+            added.insert(n, i.index());
+        }
     }
     g
 }
 
-fn assign_level(g: Digraph) -> (Digraph, usize) {
-    let mut res = g.clone();
-    let mut max_level = 0;
-    g.node_indices()
-        .filter(|n| g.neighbors_directed(*n, Incoming).next().is_none())
-        .for_each(|i| {
-            res[i].level = 1;
+/// Reverse edges that go against a greedy order of the nodes, which leaves no cycles
+///
+/// The order is the one of Eades, Lin and Smyth: sinks go to the end, sources to the
+/// front, and otherwise the node with most more outgoing than incoming edges goes next.
+/// Reversed edges swap their arrowheads and are kept apart from an edge the other way,
+/// so both keep their line, heads and label.
+fn break_cycles(g: Digraph) -> Digraph {
+    let n = g.node_count();
+    let mut removed = vec![false; n];
+    let (mut front, mut back) = (Vec::new(), Vec::new());
+    let degree = |removed: &[bool], i: NodeIndex, dir: Direction| {
+        g.neighbors_directed(i, dir)
+            .filter(|m| !removed[m.index()])
+            .count()
+    };
+    while front.len() + back.len() < n {
+        let remaining: Vec<NodeIndex> = g.node_indices().filter(|i| !removed[i.index()]).collect();
+        let next = if let Some(i) = remaining
+            .iter()
+            .find(|i| degree(&removed, **i, Outgoing) == 0)
+        {
+            back.push(*i);
+            *i
+        } else if let Some(i) = remaining
+            .iter()
+            .find(|i| degree(&removed, **i, Incoming) == 0)
+        {
+            front.push(*i);
+            *i
+        } else {
+            // Ties go to the node written first
+            let i = *remaining
+                .iter()
+                .rev()
+                .max_by_key(|i| {
+                    degree(&removed, **i, Outgoing) as isize
+                        - degree(&removed, **i, Incoming) as isize
+                })
+                .unwrap();
+            front.push(i);
+            i
+        };
+        removed[next.index()] = true;
+    }
+    let mut order = vec![0; n];
+    for (idx, i) in front.into_iter().chain(back.into_iter().rev()).enumerate() {
+        order[i.index()] = idx;
+    }
+
+    let mut res = g.clone();
+    res.clear_edges();
+    for e in g.edge_indices() {
+        let (mut s, mut t) = g.edge_endpoints(e).unwrap();
+        let mut edge = g[e].clone();
+        if order[s.index()] > order[t.index()] {
+            (s, t) = (t, s);
+            edge.arrows = Arrows::from_ends(edge.arrows.down(), edge.arrows.up());
+            edge.reversed = true;
+        }
+        res.add_edge(s, t, edge);
+    }
+    res
+}
+
+fn assign_level(g: Digraph) -> (Digraph, usize) {
+    let mut res = g.clone();
+    // Nodes with no edges but a loop are alone in the first level
+    let mut max_level = 1;
+    g.node_indices()
+        .filter(|n| g.neighbors_directed(*n, Incoming).next().is_none())
+        .for_each(|i| {
+            res[i].level = 1;
         });
     res.node_indices()
         .filter(|n| g.neighbors_directed(*n, Incoming).next().is_some())
@@ -661,17 +1632,30 @@ fn add_dummy(g: Digraph) -> Digraph {
             let end = g[t].level;
             edge_to_remove.push(e);
             let mut last_node = s;
+            // Every part keeps the arrowheads, the label goes with the first part
+            let mut label = g[e].label.clone();
             ((start + 1)..end).for_each(|i| {
                 let cur_node = res.add_node(NodeData {
                     level: i,
                     dummy: true,
                     width: 1,
+                    height: 3,
+                    // Only edges between nodes of the same cluster run inside it
+                    cluster: g[s].cluster.filter(|c| g[t].cluster == Some(*c)),
                     ..Default::default()
                 });
-                res.update_edge(last_node, cur_node, EdgeData::default());
+                let edge = EdgeData {
+                    label: label.take(),
+                    ..g[e].clone()
+                };
+                res.update_edge(last_node, cur_node, edge);
                 last_node = cur_node;
             });
-            res.update_edge(last_node, t, EdgeData::default());
+            let edge = EdgeData {
+                label,
+                ..g[e].clone()
+            };
+            res.update_edge(last_node, t, edge);
         }
     }
     for e in edge_to_remove.into_iter() {
@@ -680,6 +1664,36 @@ fn add_dummy(g: Digraph) -> Digraph {
     res
 }
 
+/// Find the levels each cluster spans, and put a dummy node without edges in the levels
+/// of the span that have no nodes of the cluster, to hold its place there
+fn hold_clusters(g: Digraph, clusters: &mut [Cluster]) -> Digraph {
+    let mut res = g.clone();
+    for (idx, c) in clusters.iter_mut().enumerate() {
+        let levels: Vec<usize> = g
+            .node_weights()
+            .filter(|n| n.cluster == Some(idx))
+            .map(|n| n.level)
+            .collect();
+        c.levels = (
+            levels.iter().copied().min().unwrap(),
+            levels.iter().copied().max().unwrap(),
+        );
+        for l in c.levels.0..=c.levels.1 {
+            if !levels.contains(&l) {
+                res.add_node(NodeData {
+                    level: l,
+                    dummy: true,
+                    width: 1,
+                    height: 3,
+                    cluster: Some(idx),
+                    ..Default::default()
+                });
+            }
+        }
+    }
+    res
+}
+
 fn level_cnt(g: &Digraph, max_level: usize) -> Vec<usize> {
     let mut res: Vec<usize> = vec![0; max_level];
     for n in g.node_indices() {
@@ -688,158 +1702,446 @@ fn level_cnt(g: &Digraph, max_level: usize) -> Vec<usize> {
     res
 }
 
-fn replace_text(g: Digraph, a: &HashMap<&str, &str>) -> Digraph {
+/// Set the text and size of nodes
+///
+/// Text is split into lines at `\n`, and lines are wrapped to fit `max_node_width` if it is
+/// set. Width is measured across levels and height along them, so side by side levels get
+/// nodes as tall as their edges and lines need and as wide as their text.
+fn replace_text(
+    g: Digraph,
+    a: &HashMap<&str, String>,
+    records: &HashMap<&str, Table<String>>,
+    shapes: &HashMap<&str, Shape>,
+    attribs: &Attrib,
+) -> Digraph {
     let mut res = g.clone();
-    for n in g.node_indices() {
-        let mut len = g[n].value.len();
-        if a.contains_key(g[n].value.as_str()) {
-            res[n].value = a[g[n].value.as_str()].to_string();
-            if res[n].value.len() > len {
-                len = res[n].value.len();
+    let max_text_width = attribs.max_node_width.saturating_sub(4).max(1);
+    let wrap = |value: &str| -> Vec<String> {
+        value
+            .split("\\n")
+            .flat_map(|line| {
+                if attribs.max_node_width > 0 && display_width(line) > max_text_width {
+                    wrap_text(line, max_text_width)
+                } else {
+                    vec![line.to_string()]
+                }
+            })
+            .collect()
+    };
+    for n in g.node_indices().filter(|n| !g[*n].dummy) {
+        res[n].shape = shapes.get(g[n].value.as_str()).copied().unwrap_or_default();
+        let (rows, len) = if let Some(record) = records.get(g[n].value.as_str()) {
+            // Compartments are wrapped line by line, and a row apart
+            let mut record = record.clone();
+            for row in record.cells.iter_mut() {
+                row[0].value = row[0]
+                    .value
+                    .split('\n')
+                    .flat_map(wrap)
+                    .collect::<Vec<_>>()
+                    .join("\n");
+            }
+            let mut rows = 0;
+            for (i, height) in record.row_height().into_iter().enumerate() {
+                if i > 0 {
+                    if attribs.direction.is_horizontal() {
+                        res[n].separators.push(rows + 1);
+                    }
+                    rows += 1;
+                }
+                rows += height;
+            }
+            let len = record.col_width()[0];
+            res[n].record = Some(record);
+            (rows, len)
+        } else {
+            let lines = wrap(a.get(g[n].value.as_str()).unwrap_or(&g[n].value));
+            let len = lines.iter().map(|l| display_width(l)).max().unwrap_or(0);
+            res[n].value = lines.join("\n");
+            (lines.len(), len)
+        };
+        let degree = max(
+            g.neighbors_directed(n, Incoming).count(),
+            g.neighbors_directed(n, Outgoing).count(),
+        );
+        // Side by side, edges meet the node between the lines of its compartments
+        (res[n].width, res[n].height) = if attribs.direction.is_horizontal() {
+            (
+                max(max(degree, 1) + res[n].separators.len(), rows) + 2,
+                len + 4,
+            )
+        } else {
+            (max(len, degree) + 4, rows + 2)
+        };
+        // A loop needs two rows on the side of the node, then a column, and one more for
+        // the tail of a triangle, then one for its label below it or the label with a space
+        // before it
+        if let Some(e) = &g[n].self_loop {
+            let len = e.label.as_deref().map_or(0, display_width);
+            if attribs.direction.is_horizontal() {
+                res[n].height = max(res[n].height, len + 2);
+                res[n].loop_width = 1 + (len > 0) as usize;
+            } else {
+                res[n].height = max(res[n].height, 4);
+                res[n].loop_width =
+                    loop_tail(e, attribs.direction) + if len > 0 { len + 2 } else { 1 };
+            }
+        }
+    }
+    res
+}
+
+/// Columns a loop beside a node takes before it turns, which leave room for the tail of
+/// a triangle pointing into the node
+fn loop_tail(e: &EdgeData, direction: attrib::Direction) -> usize {
+    (e.head == Head::Triangle && !direction.is_horizontal()) as usize
+}
+
+/// Order the nodes in each level to reduce edge crossings
+///
+/// The levels are swept down and up in turns, sorting each level by the median position
+/// of the neighbors in the level just swept, then adjacent nodes are swapped while that
+/// removes crossings. The order with the fewest crossings is kept.
+fn permute(g: Digraph, levels: &[usize], rng: &mut impl Rng) -> Digraph {
+    const MAX_SWEEPS: usize = 24;
+    const MAX_SWEEPS_WITHOUT_IMPROVEMENT: usize = 4;
+
+    // Start from a shuffled order, so different seeds lead to different layouts
+    let mut order: Vec<Vec<NodeIndex>> = (1..=levels.len())
+        .map(|l| {
+            let mut level: Vec<NodeIndex> = g.node_indices().filter(|i| g[*i].level == l).collect();
+            level.shuffle(rng);
+            level
+        })
+        .collect();
+    let mut pos = vec![0; g.node_count()];
+    for level in order.iter_mut() {
+        update_pos(&mut pos, level);
+        group_clusters(&g, level, &mut pos);
+    }
+
+    let mut best = order.clone();
+    let mut best_crossings = total_crossings(&g, &order, &pos);
+    let mut sweeps_without_improvement = 0;
+    for sweep in 0..MAX_SWEEPS {
+        if best_crossings == 0 || sweeps_without_improvement >= MAX_SWEEPS_WITHOUT_IMPROVEMENT {
+            break;
+        }
+        if sweep % 2 == 0 {
+            for level in order.iter_mut().skip(1) {
+                sort_by_median(&g, level, &mut pos, Incoming);
+                group_clusters(&g, level, &mut pos);
+            }
+        } else {
+            for level in order.iter_mut().rev().skip(1) {
+                sort_by_median(&g, level, &mut pos, Outgoing);
+                group_clusters(&g, level, &mut pos);
             }
         }
-        if g.neighbors_directed(n, Incoming).count() > len {
-            len = g.neighbors_directed(n, Incoming).count();
+        transpose(&g, &mut order, &mut pos);
+
+        let crossings = total_crossings(&g, &order, &pos);
+        if crossings < best_crossings {
+            best = order.clone();
+            best_crossings = crossings;
+            sweeps_without_improvement = 0;
+        } else {
+            sweeps_without_improvement += 1;
         }
-        if g.neighbors_directed(n, Outgoing).count() > len {
-            len = g.neighbors_directed(n, Outgoing).count();
+    }
+
+    let rank = order_clusters(&g, &mut best);
+    separate_clusters(&g, &mut best, &rank);
+    let mut res = g;
+    for level in best.iter() {
+        for (perm, n) in level.iter().enumerate() {
+            res[*n].permutation = perm;
         }
-        res[n].width = if g[n].dummy { 1 } else { len + 4 };
-        res[n].height = 3;
     }
     res
 }
 
-fn permute(g: Digraph, levels: &[usize]) -> Digraph {
-    let mut res = g.clone();
-    for l in 1..=levels.len() {
-        let mut perm = 0;
-        g.node_indices().filter(|i| g[*i].level == l).for_each(|i| {
-            res[i].permutation = perm;
-            res[i].temp_perm = perm as f32;
-            perm += 1;
-        });
+/// Bring the nodes of each cluster in a level together, where they are on average
+fn group_clusters(g: &Digraph, level: &mut [NodeIndex], pos: &mut [usize]) {
+    let mut sums: HashMap<usize, (usize, usize)> = HashMap::new();
+    for (i, n) in level.iter().enumerate() {
+        if let Some(c) = g[*n].cluster {
+            let sum = sums.entry(c).or_default();
+            *sum = (sum.0 + i, sum.1 + 1);
+        }
+    }
+    if sums.is_empty() {
+        return;
     }
-    let largest_level = levels
+    let mut keys: Vec<(f64, usize, usize, NodeIndex)> = level
         .iter()
         .enumerate()
-        .max_by_key(|&(_, &val)| val)
-        .map(|(index, _)| index)
-        .unwrap()
-        + 1;
-    let mut outer_optimal = res.clone();
-    let mut outer_optimal_crossings = cal_crossings(&res, levels.len());
-    const OUTER_MAX_LOOP: usize = 23;
-    let mut outer_loop_cnt = 0;
-    loop {
-        if outer_loop_cnt >= OUTER_MAX_LOOP {
-            break;
+        .map(|(i, n)| match g[*n].cluster {
+            Some(c) => (sums[&c].0 as f64 / sums[&c].1 as f64, c + 1, i, *n),
+            None => (i as f64, 0, i, *n),
+        })
+        .collect();
+    keys.sort_by(|a, b| a.0.total_cmp(&b.0).then((a.1, a.2).cmp(&(b.1, b.2))));
+    for (idx, (_, _, _, n)) in keys.into_iter().enumerate() {
+        level[idx] = n;
+    }
+    update_pos(pos, level);
+}
+
+/// Put the clusters in the same order in every level, so that their boxes can stand
+/// side by side, and give the place of each cluster in that order
+fn order_clusters(g: &Digraph, order: &mut [Vec<NodeIndex>]) -> HashMap<usize, usize> {
+    let mut sums: HashMap<usize, (f64, usize)> = HashMap::new();
+    for level in order.iter() {
+        for (i, n) in level.iter().enumerate() {
+            if let Some(c) = g[*n].cluster {
+                let sum = sums.entry(c).or_default();
+                *sum = (sum.0 + (i as f64 + 0.5) / level.len() as f64, sum.1 + 1);
+            }
         }
-        let inner = {
-            let mut inner_optimal = get_rand_permute_level(
-                res.clone(),
-                largest_level,
-                levels[largest_level.wrapping_sub(1)],
-            );
-            let mut inner_optimal_crossings = cal_crossings(&inner_optimal, levels.len());
-            const INNER_MAX_LOOP: usize = 23;
-            let mut inner_loop_cnt = 0;
-            let mut step: isize = -1;
-            let mut current_level = largest_level;
-            loop {
-                if inner_loop_cnt >= INNER_MAX_LOOP {
-                    break;
-                }
-
-                current_level = current_level.wrapping_add_signed(step);
-                if current_level <= 1 {
-                    step = 1;
-                    inner_loop_cnt += 1;
-                }
-                if current_level >= levels.len() {
-                    step = -1;
-                    inner_loop_cnt += 1;
-                }
-                let mut new_res = res.clone();
-                let mut perms: Vec<(NodeIndex, f32)> = Vec::new();
-                for n in res.node_indices() {
-                    if res[n].level == current_level.wrapping_add_signed(step) {
-                        if res
-                            .neighbors_directed(n, if step == -1 { Outgoing } else { Incoming })
-                            .next()
-                            .is_some()
-                        {
-                            let temp_perm = res
-                                .neighbors_directed(n, if step == -1 { Outgoing } else { Incoming })
-                                .fold(0, |acc, c| acc + res[c].permutation)
-                                as f32
-                                / res
-                                    .neighbors_directed(
-                                        n,
-                                        if step == -1 { Outgoing } else { Incoming },
-                                    )
-                                    .count() as f32;
-                            new_res[n].temp_perm = temp_perm;
-                            perms.push((n, temp_perm));
-                        } else {
-                            perms.push((n, res[n].temp_perm));
-                        }
-                    }
-                }
-                perms.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap());
-                for (idx, (i, _)) in perms.into_iter().enumerate() {
-                    new_res[i].permutation = idx;
+    }
+    let place = |c: usize| sums[&c].0 / sums[&c].1 as f64;
+    let mut ranked: Vec<usize> = sums.keys().copied().collect();
+    ranked.sort_by(|a, b| place(*a).total_cmp(&place(*b)).then(a.cmp(b)));
+    for level in order.iter_mut() {
+        let chunks: Vec<Vec<NodeIndex>> = level
+            .chunk_by(|a, b| g[*a].cluster == g[*b].cluster)
+            .map(|c| c.to_vec())
+            .collect();
+        let mut blocks: Vec<&Vec<NodeIndex>> = chunks
+            .iter()
+            .filter(|c| g[c[0]].cluster.is_some())
+            .collect();
+        blocks.sort_by(|a, b| {
+            let (a, b) = (g[a[0]].cluster.unwrap(), g[b[0]].cluster.unwrap());
+            place(a).total_cmp(&place(b)).then(a.cmp(&b))
+        });
+        let mut blocks = blocks.into_iter();
+        *level = chunks
+            .iter()
+            .flat_map(|c| match g[c[0]].cluster {
+                Some(_) => blocks.next().unwrap(),
+                None => c,
+            })
+            .copied()
+            .collect();
+    }
+    ranked
+        .into_iter()
+        .enumerate()
+        .map(|(i, c)| (c, i))
+        .collect()
+}
+
+/// Keep edges out of the clusters they are not part of
+///
+/// Places among the clusters are counted by how many clusters of the order come before.
+/// Nodes outside clusters that have edges to each other between levels of a cluster are
+/// moved to one place, so the edges run beside the clusters. The place is the one most of
+/// them are at already, or next to a cluster they have edges to.
+fn separate_clusters(g: &Digraph, order: &mut [Vec<NodeIndex>], rank: &HashMap<usize, usize>) {
+    if rank.is_empty() {
+        return;
+    }
+    let places = rank.len() + 1;
+    let mut spans: HashMap<usize, (usize, usize)> = HashMap::new();
+    for n in g.node_weights() {
+        if let Some(c) = n.cluster {
+            let span = spans.entry(c).or_insert((n.level, n.level));
+            *span = (min(span.0, n.level), max(span.1, n.level));
+        }
+    }
+    let inside = |a: NodeIndex, b: NodeIndex| {
+        let (upper, lower) = (min(g[a].level, g[b].level), max(g[a].level, g[b].level));
+        spans.values().any(|s| s.0 <= upper && lower <= s.1)
+    };
+
+    let mut groups = UnionFind::new(g.node_count());
+    for e in g.edge_references() {
+        let (s, t) = (e.source(), e.target());
+        if g[s].cluster.is_none() && g[t].cluster.is_none() && inside(s, t) {
+            groups.union(s.index(), t.index());
+        }
+    }
+    let mut votes: HashMap<usize, Vec<usize>> = HashMap::new();
+    for level in order.iter() {
+        for (i, n) in level.iter().enumerate() {
+            if g[*n].cluster.is_some() {
+                continue;
+            }
+            let vote = votes
+                .entry(groups.find(n.index()))
+                .or_insert_with(|| vec![0; places]);
+            // Where the node is now, between the clusters before and after it
+            let first = level[..i]
+                .iter()
+                .filter_map(|m| g[*m].cluster.map(|c| rank[&c] + 1))
+                .max()
+                .unwrap_or(0);
+            let last = level[i..]
+                .iter()
+                .filter_map(|m| g[*m].cluster.map(|c| rank[&c]))
+                .min()
+                .unwrap_or(places - 1);
+            (first..=last).for_each(|p| vote[p] += 1);
+            // Next to the clusters it has edges to
+            for m in g.neighbors_undirected(*n) {
+                if let Some(c) = g[m].cluster.filter(|_| inside(*n, m)) {
+                    vote[rank[&c]] += 2;
+                    vote[rank[&c] + 1] += 2;
                 }
-                res = new_res;
-                let res_crossings = cal_crossings(&res, levels.len());
-                if res_crossings < inner_optimal_crossings {
-                    inner_optimal_crossings = res_crossings;
-                    inner_optimal = res.clone();
+            }
+        }
+    }
+    let place: HashMap<usize, usize> = votes
+        .into_iter()
+        .map(|(group, vote)| {
+            let best = (0..places).rev().max_by_key(|p| vote[*p]).unwrap();
+            (group, best)
+        })
+        .collect();
+
+    for level in order.iter_mut() {
+        let mut moved: Vec<Vec<NodeIndex>> = vec![Vec::new(); places * 2];
+        for chunk in level.chunk_by(|a, b| g[*a].cluster == g[*b].cluster) {
+            match g[chunk[0]].cluster {
+                Some(c) => moved[rank[&c] * 2 + 1].extend_from_slice(chunk),
+                None => {
+                    for n in chunk {
+                        moved[place[&groups.find(n.index())] * 2].push(*n);
+                    }
                 }
             }
-            (inner_optimal, inner_optimal_crossings)
-        };
-        if inner.1 < outer_optimal_crossings {
-            outer_optimal_crossings = inner.1;
-            outer_optimal = inner.0;
         }
-        outer_loop_cnt += 1;
+        *level = moved.concat();
     }
-    outer_optimal
 }
 
-fn get_rand_permute_level(g: Digraph, max_level: usize, level_len: usize) -> Digraph {
-    let mut res = g.clone();
-    let sequence: Vec<usize> = (0..level_len).collect();
-    let mut rand_perm = sequence.clone();
-    for i in 1..level_len {
-        let j = thread_rng().gen_range(0..=i);
-        rand_perm[i] = rand_perm[j];
-        rand_perm[j] = sequence[i];
-    }
-    let mut cur = 0;
-    for n in g.node_indices() {
-        if g[n].level == max_level {
-            res[n].permutation = rand_perm[cur];
-            cur += 1;
+fn update_pos(pos: &mut [usize], level: &[NodeIndex]) {
+    for (idx, n) in level.iter().enumerate() {
+        pos[n.index()] = idx;
+    }
+}
+
+/// Sort a level by the median position of each node's neighbors in direction `dir`,
+/// breaking ties by their barycenter
+///
+/// Nodes without such neighbors keep their place.
+fn sort_by_median(g: &Digraph, level: &mut [NodeIndex], pos: &mut [usize], dir: Direction) {
+    let mut keys: Vec<(f64, f64, NodeIndex)> = level
+        .iter()
+        .map(|n| {
+            let mut p: Vec<usize> = g
+                .neighbors_directed(*n, dir)
+                .map(|m| pos[m.index()])
+                .collect();
+            if p.is_empty() {
+                let own = pos[n.index()] as f64;
+                return (own, own, *n);
+            }
+            p.sort_unstable();
+            let barycenter = p.iter().sum::<usize>() as f64 / p.len() as f64;
+            (weighted_median(&p), barycenter, *n)
+        })
+        .collect();
+    keys.sort_by(|a, b| a.0.total_cmp(&b.0).then(a.1.total_cmp(&b.1)));
+    for (idx, (_, _, n)) in keys.into_iter().enumerate() {
+        level[idx] = n;
+    }
+    update_pos(pos, level);
+}
+
+/// Median of sorted positions, leaning towards the side where the positions are
+/// packed more closely when there are two middle values
+fn weighted_median(p: &[usize]) -> f64 {
+    let m = p.len() / 2;
+    if p.len() % 2 == 1 {
+        p[m] as f64
+    } else if p.len() == 2 {
+        (p[0] + p[1]) as f64 / 2.0
+    } else {
+        let left = (p[m - 1] - p[0]) as f64;
+        let right = (p[p.len() - 1] - p[m]) as f64;
+        if left + right == 0.0 {
+            (p[m - 1] + p[m]) as f64 / 2.0
+        } else {
+            (p[m - 1] as f64 * right + p[m] as f64 * left) / (left + right)
         }
     }
-    res
 }
 
-fn cal_crossings(g: &Digraph, max_level: usize) -> usize {
-    let mut cnt = 0;
-    for l in 1..max_level {
-        let mut lines_level: Vec<(usize, usize)> = Vec::new();
-        for e in g.edge_indices() {
-            if g[g.edge_endpoints(e).unwrap().0].level == l {
-                lines_level.push((
-                    g[g.edge_endpoints(e).unwrap().0].permutation,
-                    g[g.edge_endpoints(e).unwrap().1].permutation,
-                ));
+/// Swap adjacent nodes while that reduces the crossings with both neighboring levels
+fn transpose(g: &Digraph, order: &mut [Vec<NodeIndex>], pos: &mut [usize]) {
+    let mut improved = true;
+    while improved {
+        improved = false;
+        for level in order.iter_mut() {
+            for j in 0..level.len().saturating_sub(1) {
+                let (u, v) = (level[j], level[j + 1]);
+                // Swapping a node into or out of a cluster would split it
+                if g[u].cluster == g[v].cluster
+                    && pair_crossings(g, v, u, pos) < pair_crossings(g, u, v, pos)
+                {
+                    level.swap(j, j + 1);
+                    pos[u.index()] = j + 1;
+                    pos[v.index()] = j;
+                    improved = true;
+                }
             }
         }
-        cnt += crossings(&lines_level);
+    }
+}
+
+/// Crossings between the edges of `u` and `v` when `u` is placed left of `v`
+fn pair_crossings(g: &Digraph, u: NodeIndex, v: NodeIndex, pos: &[usize]) -> usize {
+    [Incoming, Outgoing]
+        .into_iter()
+        .map(|dir| {
+            g.neighbors_directed(u, dir)
+                .map(|a| {
+                    g.neighbors_directed(v, dir)
+                        .filter(|b| pos[a.index()] > pos[b.index()])
+                        .count()
+                })
+                .sum::<usize>()
+        })
+        .sum()
+}
+
+fn total_crossings(g: &Digraph, order: &[Vec<NodeIndex>], pos: &[usize]) -> usize {
+    order
+        .windows(2)
+        .map(|w| level_crossings(g, &w[0], w[1].len(), pos))
+        .sum()
+}
+
+/// Crossings of the edges between `upper` and the level below it, which has `lower_len`
+/// nodes, counted as inversions with a Fenwick tree
+fn level_crossings(g: &Digraph, upper: &[NodeIndex], lower_len: usize, pos: &[usize]) -> usize {
+    let mut edges: Vec<(usize, usize)> = upper
+        .iter()
+        .flat_map(|u| {
+            g.neighbors_directed(*u, Outgoing)
+                .map(|v| (pos[u.index()], pos[v.index()]))
+        })
+        .collect();
+    edges.sort_unstable();
+    let mut tree = vec![0; lower_len + 1];
+    let mut cnt = 0;
+    for (inserted, (_, v)) in edges.into_iter().enumerate() {
+        // Count the edges inserted so far that end right of `v`
+        let mut not_greater = 0;
+        let mut i = v + 1;
+        while i > 0 {
+            not_greater += tree[i];
+            i -= i & i.wrapping_neg();
+        }
+        cnt += inserted - not_greater;
+        let mut i = v + 1;
+        while i <= lower_len {
+            tree[i] += 1;
+            i += i & i.wrapping_neg();
+        }
     }
     cnt
 }
@@ -913,14 +2215,330 @@ struct NodeData {
     height: usize,
     pos: (usize, usize),
     value: String,
+    shape: Shape,
     dummy: bool,
     permutation: usize,
-    temp_perm: f32,
+    /// Index of the cluster the node is drawn in
+    cluster: Option<usize>,
+    /// Compartments of a record node, one per row
+    record: Option<Table<String>>,
+    /// Columns from the left of a record node of the lines between its compartments, which
+    /// edges cannot meet once levels are side by side
+    separators: Vec<usize>,
+    /// Edge from the node to itself
+    self_loop: Option<EdgeData>,
+    /// Columns right of the node taken by its loop
+    loop_width: usize,
+}
+
+/// Box drawn around a group of nodes, with its label on the top edge
+#[derive(Debug, Default, Clone)]
+struct Cluster {
+    label: String,
+    /// First and last level with nodes of the cluster
+    levels: (usize, usize),
+    /// Columns put in before the right edge, for labels of edges that leave the cluster
+    room: usize,
+}
+
+impl Cluster {
+    /// Level whose nodes are next to the title once the buffer is oriented
+    fn title_level(&self, direction: attrib::Direction) -> usize {
+        match direction {
+            attrib::Direction::BottomTop | attrib::Direction::RightLeft => self.levels.1,
+            attrib::Direction::TopBottom | attrib::Direction::LeftRight => self.levels.0,
+        }
+    }
+
+    /// Columns from the left edge of the cluster to its first node in `level`
+    ///
+    /// Edges cross the edge of the cluster over its nodes, so the title needs room of its
+    /// own when it runs across levels.
+    fn margin(&self, level: usize, direction: attrib::Direction) -> usize {
+        if !direction.is_horizontal() && level == self.title_level(direction) {
+            display_width(&self.label) + 4
+        } else {
+            2
+        }
+    }
+}
+
+/// Left and right columns of the edges of cluster `idx`, before the buffer is oriented
+fn cluster_bounds(
+    g: &Digraph,
+    idx: usize,
+    cluster: &Cluster,
+    direction: attrib::Direction,
+) -> (usize, usize) {
+    let members: Vec<&NodeData> = g
+        .node_weights()
+        .filter(|n| n.cluster == Some(idx))
+        .collect();
+    let left = members
+        .iter()
+        .map(|n| n.pos.0 - cluster.margin(n.level, direction))
+        .min()
+        .unwrap();
+    let mut right = members
+        .iter()
+        .map(|n| n.pos.0 + n.width + n.loop_width + 1)
+        .max()
+        .unwrap();
+    if !direction.is_horizontal() {
+        right = max(right, left + display_width(&cluster.label) + 5);
+    }
+    (left, right + cluster.room)
+}
+
+/// Border a node is drawn with
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub(super) enum Shape {
+    #[default]
+    Box,
+    Round,
+    Double,
+    Bold,
+    Diamond,
+    None,
+}
+
+impl Shape {
+    /// Corners, then edges, then junctions of edges, each in the order top left, top right,
+    /// bottom left, bottom right for corners and top, bottom, left, right for the others
+    fn palette(&self, style: Style) -> [char; 12] {
+        let palette = match (style, self) {
+            (Style::Ascii, Shape::Box) => "++++--||++++",
+            (Style::Ascii, Shape::Round) => "..''--||++++",
+            (Style::Ascii, Shape::Double) => "++++==||++++",
+            (Style::Ascii, Shape::Bold) => "########++++",
+            (Style::Ascii, Shape::Diamond) => "/\\\\/--<>++++",
+            (Style::Ascii, Shape::None) => "        ||--",
+            (Style::Unicode, Shape::Box) => "┌┐└┘──││┴┬┤├",
+            (Style::Unicode, Shape::Round) => "╭╮╰╯──││┴┬┤├",
+            (Style::Unicode, Shape::Double) => "╔╗╚╝══║║╧╤╢╟",
+            (Style::Unicode, Shape::Bold) => "┏┓┗┛━━┃┃┷┯┨┠",
+            (Style::Unicode, Shape::Diamond) => "╱╲╲╱──<>┴┬┤├",
+            (Style::Unicode, Shape::None) => "        ││──",
+        };
+        let mut res = [' '; 12];
+        for (i, c) in palette.chars().enumerate() {
+            res[i] = c;
+        }
+        res
+    }
+}
+
+impl FromStr for Shape {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "box" => Ok(Self::Box),
+            "round" => Ok(Self::Round),
+            "double" => Ok(Self::Double),
+            "bold" => Ok(Self::Bold),
+            "diamond" => Ok(Self::Diamond),
+            "none" => Ok(Self::None),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Attributes given to a node or a class in the script
+#[derive(Debug, Default)]
+struct NodeStyle<'a> {
+    shape: Option<Shape>,
+    class: Option<Span<'a>>,
+}
+
+impl<'a> NodeStyle<'a> {
+    fn update(&mut self, attribs: Pair<'a, Rule>) -> anyhow::Result<()> {
+        for attrib in attribs.into_inner() {
+            let mut attrib = attrib.into_inner();
+            let (key, value) = (attrib.next().unwrap(), attrib.next().unwrap());
+            match key.as_str() {
+                "shape" => {
+                    self.shape = Some(value.as_str().parse().map_err(|_| {
+                        ScriptError::new(
+                            ErrorKind::Parsing,
+                            format!(
+                                "unknown shape: {}, expected one of box, round, double, bold, diamond, none",
+                                value.as_str()
+                            ),
+                        )
+                        .with_span(value.as_span())
+                    })?)
+                }
+                "class" => self.class = Some(value.as_span()),
+                _ => {
+                    return Err(ScriptError::new(
+                        ErrorKind::Parsing,
+                        format!("unknown node attribute: {}", key.as_str()),
+                    )
+                    .with_span(key.as_span())
+                    .into())
+                }
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Default, PartialEq, Clone)]
-struct EdgeData {
+pub(super) struct EdgeData {
     visited: bool,
+    /// Turned around to break a cycle, and drawn beside the edges that are not
+    reversed: bool,
+    arrows: Arrows,
+    line: Line,
+    head: Head,
+    pub(super) label: Option<String>,
+}
+
+/// How the line of an edge is drawn
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+enum Line {
+    #[default]
+    Solid,
+    Dashed,
+    Bold,
+}
+
+impl Line {
+    /// Turn the end of a solid line into the one of this line
+    fn restyle(&self, c: char) -> char {
+        match (self, c) {
+            (Line::Bold, '┬') => '┰',
+            (Line::Bold, '┴') => '┸',
+            (Line::Bold, '├') => '┝',
+            (Line::Bold, '┤') => '┥',
+            _ => c,
+        }
+    }
+}
+
+// Sides of a cell that lines leave it by
+const UP: u8 = 1;
+const DOWN: u8 = 2;
+const LEFT: u8 = 4;
+const RIGHT: u8 = 8;
+// Lines through a cell for each set of sides, for each kind of line
+const LINES_UNICODE: [&str; 3] = [" │││─┘┐┤─└┌├─┴┬┼", " ┆┆┆┄┘┐┤┄└┌├┄┴┬┼", " ┃┃┃━┛┓┫━┗┏┣━┻┳╋"];
+const LINES_ASCII: [&str; 3] = [" |||-+++-+++-+++", " :::.+++.+++.+++", " ###=+++=+++=+++"];
+/// Marks dummy nodes in the buffer, so that lines stop at them as at other nodes
+const DUMMY: char = '\u{1}';
+
+/// Lines through each cell, kept apart from the buffer so that lines that meet or
+/// cross can be joined. Where different kinds of lines meet the line is solid.
+struct Canvas(Vec<Vec<(u8, Line)>>);
+
+impl Canvas {
+    fn new(width: usize, height: usize) -> Self {
+        Self(vec![vec![(0, Line::Solid); width]; height])
+    }
+
+    fn add(&mut self, y: usize, x: usize, sides: u8, line: Line) {
+        let cell = &mut self.0[y][x];
+        if cell.0 == 0 {
+            cell.1 = line;
+        } else if cell.1 != line {
+            cell.1 = Line::Solid;
+        }
+        cell.0 |= sides;
+    }
+
+    /// Turn the canvas like `orient` turns the buffer, sides included
+    fn orient(self, direction: attrib::Direction) -> Self {
+        let turned = match direction {
+            attrib::Direction::TopBottom => [UP, DOWN, LEFT, RIGHT],
+            attrib::Direction::BottomTop => [DOWN, UP, LEFT, RIGHT],
+            attrib::Direction::LeftRight => [LEFT, RIGHT, UP, DOWN],
+            attrib::Direction::RightLeft => [RIGHT, LEFT, UP, DOWN],
+        };
+        let turn = |sides: u8| {
+            (0..4)
+                .filter(|i| sides & (1 << i) != 0)
+                .fold(0, |acc, i| acc | turned[i])
+        };
+        Self(
+            orient(self.0, direction)
+                .into_iter()
+                .map(|row| row.into_iter().map(|(s, l)| (turn(s), l)).collect())
+                .collect(),
+        )
+    }
+
+    fn glyph(&self, y: usize, x: usize, style: Style) -> char {
+        let (sides, line) = self.0[y][x];
+        let lines = match style {
+            Style::Ascii => LINES_ASCII,
+            Style::Unicode => LINES_UNICODE,
+        };
+        lines[line as usize].chars().nth(sides as usize).unwrap()
+    }
+}
+
+/// Ends of an edge that have an arrowhead, up being the end in the upper level
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+enum Arrows {
+    #[default]
+    Down,
+    Up,
+    Both,
+    Neither,
+}
+
+impl Arrows {
+    fn from_ends(up: bool, down: bool) -> Self {
+        match (up, down) {
+            (false, true) => Arrows::Down,
+            (true, false) => Arrows::Up,
+            (true, true) => Arrows::Both,
+            (false, false) => Arrows::Neither,
+        }
+    }
+
+    fn up(&self) -> bool {
+        matches!(self, Arrows::Up | Arrows::Both)
+    }
+
+    fn down(&self) -> bool {
+        matches!(self, Arrows::Down | Arrows::Both)
+    }
+}
+
+/// What the ends of an edge with arrowheads are drawn with
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+enum Head {
+    #[default]
+    Arrow,
+    Triangle,
+    Diamond,
+    HollowDiamond,
+}
+
+impl Head {
+    /// Index in the palette of the head at the upper or the lower end
+    fn index(&self, up: bool) -> usize {
+        match (self, up) {
+            (Head::Arrow, false) => 9,
+            (Head::Arrow, true) => 10,
+            (Head::Triangle, false) => 12,
+            (Head::Triangle, true) => 13,
+            (Head::Diamond, _) => 14,
+            (Head::HollowDiamond, _) => 15,
+        }
+    }
+
+    /// Index in the palette of the head at an end of a loop
+    fn side_index(&self) -> usize {
+        match self {
+            Head::Arrow => 17,
+            Head::Triangle => 18,
+            Head::Diamond => 14,
+            Head::HollowDiamond => 15,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -928,15 +2546,708 @@ enum Connection {
     Straight {
         from: (usize, usize),
         dummy: (bool, bool),
+        arrows: Arrows,
+        line: Line,
+        head: Head,
+        label: Option<String>,
     },
     Bent {
         x1: usize,
         x2: usize,
         y: isize,
         dummy: (bool, bool),
+        arrows: Arrows,
+        line: Line,
+        head: Head,
+        label: Option<String>,
     },
 }
 
+impl Connection {
+    /// Column where the connection leaves the upper level
+    fn start_x(&self) -> usize {
+        match self {
+            Connection::Straight { from, .. } => from.0,
+            Connection::Bent { x1, .. } => *x1,
+        }
+    }
+
+    fn line(&self) -> Line {
+        match self {
+            Connection::Straight { line, .. } | Connection::Bent { line, .. } => *line,
+        }
+    }
+
+    fn label(&self) -> Option<&str> {
+        match self {
+            Connection::Straight { label, .. } | Connection::Bent { label, .. } => label.as_deref(),
+        }
+    }
+}
+
+/// Edge label placed in the gap below level `gap`, at row `y` of the gap like bent connections
+#[derive(Debug, Clone)]
+struct Label {
+    gap: usize,
+    y: isize,
+    x: usize,
+    size: (usize, usize),
+    text: String,
+}
+
 #[derive(Parser)]
 #[grammar = "mono-diagram/grammar/dag.pest"]
 struct DagGraphParser;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lay_out(script: &str, attribs: &str) -> DagGraph {
+        let mut graph = DagGraph::digraph();
+        let mut a = graph.default_attribs();
+        a.update_from_str(attribs).unwrap();
+        graph.parse_from_str(script, a).unwrap();
+        graph
+    }
+
+    #[test]
+    fn layouts_depend_on_the_seed_alone() {
+        let script = "a->b\na->c\na->d\nb->e\nc->e\nd->f\n";
+        let render = |seed: u64| {
+            let g = lay_out(script, &format!("{{seed: {}}}", seed));
+            String::from_utf8(g.write().unwrap()).unwrap()
+        };
+        let outputs: Vec<String> = (0..8).map(render).collect();
+        for (seed, output) in outputs.iter().enumerate() {
+            assert_eq!(*output, render(seed as u64), "seed {}", seed);
+        }
+        assert!(outputs.iter().any(|o| *o != outputs[0]));
+        // Without a seed, layouts are those of seed 0
+        let g = lay_out(script, "{}");
+        assert_eq!(String::from_utf8(g.write().unwrap()).unwrap(), outputs[0]);
+    }
+
+    #[test]
+    fn text_wraps_between_words_and_breaks_long_ones() {
+        assert_eq!(
+            wrap_text("build the docs site", 9),
+            ["build the", "docs site"]
+        );
+        assert_eq!(
+            wrap_text("a supercalifragilistic b", 8),
+            ["a", "supercal", "ifragili", "stic b"]
+        );
+        // Wide characters take two columns, and are never split across lines
+        assert_eq!(wrap_text("数据库 服务器", 5), ["数据", "库", "服务", "器"]);
+        assert_eq!(wrap_text("", 4), [""]);
+    }
+
+    #[test]
+    fn nodes_wrap_to_max_node_width_unless_it_is_0() {
+        let script = "a: run the whole test suite again\na->b\n";
+        let width = |attribs: &str| {
+            let g = lay_out(script, attribs);
+            let a = g.data.node_weights().find(|n| n.level == 1).unwrap();
+            (a.width, a.height)
+        };
+        // "run the", "whole", "test", "suite" and "again" fit in 8 columns inside the box
+        assert_eq!(width("{max_node_width: 12}"), (11, 7));
+        assert_eq!(width("{max_node_width: 0}"), (34, 3));
+        assert_eq!(width("{}"), width("{max_node_width: 0}"));
+    }
+
+    /// Graph with nodes in the given levels, numbered in order, and edges between them
+    fn leveled(levels: &[usize], edges: &[(usize, usize)]) -> Digraph {
+        let mut g = Digraph::new();
+        for level in levels {
+            g.add_node(NodeData {
+                level: *level,
+                ..Default::default()
+            });
+        }
+        for (s, t) in edges {
+            g.add_edge(NodeIndex::new(*s), NodeIndex::new(*t), EdgeData::default());
+        }
+        g
+    }
+
+    fn order_of(g: &Digraph, levels: usize) -> Vec<Vec<usize>> {
+        (1..=levels)
+            .map(|l| {
+                let mut level: Vec<NodeIndex> =
+                    g.node_indices().filter(|n| g[*n].level == l).collect();
+                level.sort_by_key(|n| g[*n].permutation);
+                level.into_iter().map(|n| n.index()).collect()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn level_crossings_agree_with_counting_pairs() {
+        // Every edge from the upper level to the lower, with the lower level reversed
+        let mut edges = Vec::new();
+        for u in 0..4 {
+            for v in 4..8 {
+                if (u + v) % 3 != 0 {
+                    edges.push((u, v));
+                }
+            }
+        }
+        let g = leveled(&[1, 1, 1, 1, 2, 2, 2, 2], &edges);
+        let upper: Vec<NodeIndex> = (0..4).map(NodeIndex::new).collect();
+        let pos = [0, 1, 2, 3, 3, 2, 1, 0];
+        let lines: Vec<(usize, usize)> = edges.iter().map(|(u, v)| (pos[*u], pos[*v])).collect();
+        assert!(crossings(&lines) > 0);
+        assert_eq!(level_crossings(&g, &upper, 4, &pos), crossings(&lines));
+        assert_eq!(crossings(&[(0, 1), (1, 0)]), 1);
+        assert_eq!(crossings(&[(0, 0), (0, 1), (1, 1)]), 0);
+    }
+
+    #[test]
+    fn weighted_median_leans_to_packed_side() {
+        assert_eq!(weighted_median(&[4]), 4.0);
+        assert_eq!(weighted_median(&[1, 3, 8]), 3.0);
+        assert_eq!(weighted_median(&[2, 6]), 4.0);
+        // The left pair is packed closer, so the median is nearer to it
+        assert!(weighted_median(&[0, 1, 2, 9]) < 1.5);
+        assert_eq!(weighted_median(&[3, 3, 3, 3]), 3.0);
+    }
+
+    #[test]
+    fn sort_by_median_follows_the_level_above() {
+        let g = leveled(&[1, 1, 1, 2, 2, 2], &[(0, 5), (1, 4), (2, 3)]);
+        let mut pos = vec![0, 1, 2, 0, 1, 2];
+        let mut level: Vec<NodeIndex> = (3..6).map(NodeIndex::new).collect();
+        sort_by_median(&g, &mut level, &mut pos, Incoming);
+        assert_eq!(level, [5, 4, 3].map(NodeIndex::new));
+        assert_eq!(&pos[3..], [2, 1, 0]);
+    }
+
+    #[test]
+    fn transpose_swaps_nodes_that_cross() {
+        let g = leveled(&[1, 1, 2, 2], &[(0, 3), (1, 2)]);
+        let mut order = vec![
+            vec![NodeIndex::new(0), NodeIndex::new(1)],
+            vec![NodeIndex::new(2), NodeIndex::new(3)],
+        ];
+        let mut pos = vec![0, 1, 0, 1];
+        assert_eq!(total_crossings(&g, &order, &pos), 1);
+        transpose(&g, &mut order, &mut pos);
+        assert_eq!(total_crossings(&g, &order, &pos), 0);
+    }
+
+    #[test]
+    fn permute_untangles_a_ladder_from_any_start() {
+        // Two chains joined by rungs can be drawn without crossings
+        let g = leveled(
+            &[1, 1, 2, 2, 3, 3, 4, 4],
+            &[
+                (0, 2),
+                (2, 4),
+                (4, 6),
+                (1, 3),
+                (3, 5),
+                (5, 7),
+                (0, 3),
+                (2, 5),
+                (4, 7),
+            ],
+        );
+        for seed in 0..16 {
+            let g = permute(g.clone(), &[2, 2, 2, 2], &mut StdRng::seed_from_u64(seed));
+            let order: Vec<Vec<NodeIndex>> = order_of(&g, 4)
+                .into_iter()
+                .map(|l| l.into_iter().map(NodeIndex::new).collect())
+                .collect();
+            let mut pos = vec![0; g.node_count()];
+            for level in order.iter() {
+                update_pos(&mut pos, level);
+            }
+            assert_eq!(total_crossings(&g, &order, &pos), 0, "seed {}", seed);
+        }
+    }
+
+    /// Lines of the diagrams in a script, as rendered for users
+    fn render(script: &str) -> Vec<String> {
+        crate::render_str(script)
+            .unwrap()
+            .trim_end_matches('\n')
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn break_cycles_reverses_edges_against_the_order() {
+        // A cycle of three, and two nodes with an edge each way
+        let g = leveled(&[0; 5], &[(0, 1), (1, 2), (2, 0), (3, 4), (4, 3)]);
+        let g = break_cycles(g);
+        assert!(!is_cyclic_directed(&g));
+        assert_eq!(g.edge_count(), 5);
+        let reversed: Vec<(usize, usize)> = g
+            .edge_references()
+            .filter(|e| e.weight().reversed)
+            .map(|e| (e.source().index(), e.target().index()))
+            .collect();
+        assert_eq!(reversed, [(0, 2), (3, 4)]);
+        for e in g.edge_references() {
+            let arrows = if e.weight().reversed {
+                Arrows::Up
+            } else {
+                Arrows::Down
+            };
+            assert_eq!(e.weight().arrows, arrows);
+        }
+    }
+
+    #[test]
+    fn edges_from_a_node_to_itself_become_loops() {
+        let g = lay_out("a -> a: again\na -> b\n", "{}");
+        let a = g
+            .data
+            .node_indices()
+            .find(|n| g.data[*n].value == "a")
+            .unwrap();
+        let e = g.data[a].self_loop.as_ref().unwrap();
+        assert_eq!(e.label.as_deref(), Some("again"));
+        assert_eq!(g.data.edge_count(), 1);
+        // The loop, a space, and its label
+        assert_eq!(g.data[a].loop_width, 7);
+    }
+
+    #[test]
+    fn digraph_renders_in_both_styles() {
+        let script = "a -> b: go\nb -> c\nc --> a\nb -> b\nc => d\n";
+        assert_eq!(
+            render(&format!("[digraph]\n{}", script)),
+            [
+                "┌───────┐ ",
+                "│ a     │ ",
+                "└┬─────^┘ ",
+                " │ go  ┆  ",
+                "┌V──┐  ┆  ",
+                "│ b ├┐ ┆  ",
+                "│   <┘ ┆  ",
+                "└┬──┘  ┆  ",
+                " │     ┆  ",
+                "┌V─────┴┐ ",
+                "│ c     │ ",
+                "└┰──────┘ ",
+                " ┃        ",
+                "┌V──┐     ",
+                "│ d │     ",
+                "└───┘     ",
+            ]
+        );
+        assert_eq!(
+            render(&format!("[digraph] {{style: ascii}}\n{}", script)),
+            [
+                "+-------+ ",
+                "| a     | ",
+                "++-----^+ ",
+                " | go  :  ",
+                "+v--+  :  ",
+                "| b ++ :  ",
+                "|   <+ :  ",
+                "++--+  :  ",
+                " |     :  ",
+                "+v-----++ ",
+                "| c     | ",
+                "++------+ ",
+                " #        ",
+                "+v--+     ",
+                "| d |     ",
+                "+---+     ",
+            ]
+        );
+    }
+
+    #[test]
+    fn graph_edges_have_no_arrowheads() {
+        assert!(GraphKind::Graph.edge("->").is_none());
+        assert!(GraphKind::Graph.edge("<->").is_none());
+        assert_eq!(GraphKind::Graph.edge("--").unwrap().arrows, Arrows::Neither);
+    }
+
+    #[test]
+    fn graph_renders_in_both_styles() {
+        let script = "a -- b\nb -- c\nc -- a\nb -- b\nc -- d\n";
+        assert_eq!(
+            render(&format!("[graph]\n{}", script)),
+            [
+                "┌───────┐ ",
+                "│ a     │ ",
+                "└┬─────┬┘ ",
+                "┌┴──┐  │  ",
+                "│ b ├┐ │  ",
+                "│   ├┘ │  ",
+                "└┬──┘  │  ",
+                "┌┴─────┴┐ ",
+                "│ c     │ ",
+                "└┬──────┘ ",
+                "┌┴──┐     ",
+                "│ d │     ",
+                "└───┘     ",
+            ]
+        );
+        assert_eq!(
+            render(&format!("[graph] {{style: ascii}}\n{}", script)),
+            [
+                "+-------+ ",
+                "| a     | ",
+                "++-----++ ",
+                "++--+  |  ",
+                "| b ++ |  ",
+                "|   ++ |  ",
+                "++--+  |  ",
+                "++-----++ ",
+                "| c     | ",
+                "++------+ ",
+                "++--+     ",
+                "| d |     ",
+                "+---+     ",
+            ]
+        );
+    }
+
+    #[test]
+    fn edge_styles_render_in_both_styles() {
+        // Plain, dashed and thick edges side by side
+        let script = "a -- b\na --> c\na => d\n";
+        assert_eq!(
+            render(&format!("[dag]\n{}", script)),
+            [
+                "┌─────────────┐   ",
+                "│ a           │   ",
+                "└┬─────┬─────┰┘   ",
+                " │     ┆     ┃    ",
+                "┌┴──┐ ┌V──┐ ┌V──┐ ",
+                "│ b │ │ c │ │ d │ ",
+                "└───┘ └───┘ └───┘ ",
+            ]
+        );
+        assert_eq!(
+            render(&format!("[dag] {{style: ascii}}\n{}", script)),
+            [
+                "+-------------+   ",
+                "| a           |   ",
+                "++-----+-----++   ",
+                " |     :     #    ",
+                "++--+ +v--+ +v--+ ",
+                "| b | | c | | d | ",
+                "+---+ +---+ +---+ ",
+            ]
+        );
+    }
+
+    #[test]
+    fn shapes_render_in_both_styles() {
+        let shapes = [
+            (
+                "box",
+                ["┌─────┐", "│ box │", "└┬────┘"],
+                ["+-----+", "| box |", "++----+"],
+            ),
+            (
+                "round",
+                ["╭───────╮", "│ round │", "╰┬──────╯"],
+                [".-------.", "| round |", "'+------'"],
+            ),
+            (
+                "double",
+                ["╔════════╗", "║ double ║", "╚╤═══════╝"],
+                ["+========+", "| double |", "++=======+"],
+            ),
+            (
+                "bold",
+                ["┏━━━━━━┓", "┃ bold ┃", "┗┯━━━━━┛"],
+                ["########", "# bold #", "#+######"],
+            ),
+            (
+                "diamond",
+                ["╱─────────╲", "< diamond >", "╲┬────────╱"],
+                ["/---------\\", "< diamond >", "\\+--------/"],
+            ),
+            ("none", ["", "  none", " │"], ["", "  none", " |"]),
+        ];
+        for (shape, unicode, ascii) in shapes {
+            for (style, lines) in [("unicode", unicode), ("ascii", ascii)] {
+                let script = format!(
+                    "[dag] {{style: {}}}\na -> b\na: {} {{shape: {1}}}\n",
+                    style, shape
+                );
+                // The node is on top, above the box it points to
+                let top: Vec<String> = render(&script)
+                    .iter()
+                    .take(3)
+                    .map(|l| l.trim_end().to_string())
+                    .collect();
+                assert_eq!(top, lines, "{} in {}", shape, style);
+            }
+        }
+    }
+
+    #[test]
+    fn diamonds_point_from_the_middle_of_their_sides() {
+        let script = "a -> b\na -> c\nc -> d\na: start {shape: diamond}\nb: x {shape: diamond}\nc: yy {shape: diamond}\nd: a b {shape: diamond}\n";
+        assert_eq!(
+            render(&format!(
+                "[dag] {{direction: lr, style: ascii}}\n{}",
+                script
+            )),
+            [
+                "/-------\\  /---\\   /-----\\",
+                "|       +--> x >   |     |",
+                "|       |  \\---/   |     |",
+                "< start >          < a b >",
+                "|       |  /----\\  |     |",
+                "|       +--> yy +-->     |",
+                "\\-------/  \\----/  \\-----/",
+            ]
+        );
+    }
+
+    #[test]
+    fn nodes_take_the_shape_of_their_class_unless_given_one() {
+        let g = lay_out(
+            "class decision {shape: diamond}\na {class: decision}\nb {class: decision, shape: round}\nc {shape: bold}\na -> b\nb -> c\nc -> d\n",
+            "{}",
+        );
+        let shape_of = |value: &str| {
+            g.data
+                .node_weights()
+                .find(|n| !n.dummy && n.value.contains(value))
+                .unwrap()
+                .shape
+        };
+        assert_eq!(shape_of("a"), Shape::Diamond);
+        assert_eq!(shape_of("b"), Shape::Round);
+        assert_eq!(shape_of("c"), Shape::Bold);
+        assert_eq!(shape_of("d"), Shape::Box);
+        for (script, message) in [
+            ("a {class: missing}\n", "class is not defined: missing"),
+            ("a {shape: star}\n", "unknown shape: star"),
+            ("a {colour: red}\n", "unknown node attribute: colour"),
+        ] {
+            let mut graph = DagGraph::digraph();
+            let attribs = graph.default_attribs();
+            let err = graph.parse_from_str(script, attribs).unwrap_err();
+            assert!(err.to_string().contains(message), "{}", err);
+        }
+    }
+
+    #[test]
+    fn edges_stay_out_of_clusters_they_are_not_part_of() {
+        let edges = "x->a\ny->b\nb->z\na->z\nx->z\nc->y: a label\nc->b\nw->c: label\nw->z\n";
+        for members in ["a b", "a c", "a z", "x z", "y b", "w a b"] {
+            for direction in ["tb", "lr"] {
+                for seed in 0..8 {
+                    let script = format!("cluster k {{ {} }}\n{}", members, edges);
+                    let attribs = format!("{{direction: {}, seed: {}}}", direction, seed);
+                    let g = lay_out(&script, &attribs);
+                    let (left, right) =
+                        cluster_bounds(&g.data, 0, &g.clusters[0], g.attribs.direction);
+                    let (top, bottom) = g.clusters[0].levels;
+                    let spanned = |l: usize| top <= l && l <= bottom;
+                    let outside = |n: NodeIndex| g.data[n].cluster.is_none();
+                    let side = |n: NodeIndex| g.data[n].pos.0 > right;
+                    for n in g.data.node_indices().filter(|n| outside(*n)) {
+                        let (x, w) = (g.data[n].pos.0, g.data[n].width);
+                        assert!(
+                            !spanned(g.data[n].level) || x + w < left || x > right,
+                            "{} in {}: node at {} is in the cluster",
+                            script,
+                            attribs,
+                            x
+                        );
+                    }
+                    for e in g.data.edge_references() {
+                        let (s, t) = (e.source(), e.target());
+                        if outside(s)
+                            && outside(t)
+                            && spanned(g.data[s].level)
+                            && spanned(g.data[t].level)
+                        {
+                            assert_eq!(side(s), side(t), "{} in {}", script, attribs);
+                        }
+                    }
+                    for l in g
+                        .labels
+                        .iter()
+                        .filter(|l| spanned(l.gap + 1) && spanned(l.gap + 2))
+                    {
+                        let columns = l.x..(l.x + l.size.0);
+                        assert!(
+                            !columns.contains(&left) && !columns.contains(&right),
+                            "{} in {}: label {} is on the cluster",
+                            script,
+                            attribs,
+                            l.text
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn clusters_hold_their_nodes_loops_and_title() {
+        let script = "k: a long title\ncluster k { a }\nx->a\na->a: self\na->b\nb->y\n";
+        for direction in ["tb", "bt", "lr", "rl"] {
+            let g = lay_out(script, &format!("{{direction: {}}}", direction));
+            let (left, right) = cluster_bounds(&g.data, 0, &g.clusters[0], g.attribs.direction);
+            let (top, bottom) = g.clusters[0].levels;
+            for n in g.data.node_weights().filter(|n| n.cluster == Some(0)) {
+                assert!(top <= n.level && n.level <= bottom, "{}", direction);
+                assert!(
+                    left < n.pos.0,
+                    "{}: {} starts at {}",
+                    direction,
+                    n.value,
+                    n.pos.0
+                );
+                assert!(
+                    n.pos.0 + n.width + n.loop_width < right,
+                    "{}: {} ends past {}",
+                    direction,
+                    n.value,
+                    right
+                );
+            }
+            // The title runs across the cluster once it is oriented, between its corners
+            let title = display_width(" a long title ") + 4;
+            if g.attribs.direction.is_horizontal() {
+                let first = g.level_row(top);
+                let last = g.level_row(bottom) + g.line_height[bottom - 1] - 1;
+                assert!(last - first + 1 >= title, "{}", direction);
+            } else {
+                assert!(right - left + 1 >= title, "{}", direction);
+            }
+        }
+    }
+
+    #[test]
+    fn clusters_have_room_for_their_left_edge() {
+        // K is measured while J, left of it, has not made way for its own edges yet
+        let script = "h->f\ni->g\nj->e\ne->h\ncluster K { j }\ncluster J { i f }\n";
+        for direction in ["tb", "lr"] {
+            let g = lay_out(script, &format!("{{direction: {}}}", direction));
+            for (idx, c) in g.clusters.iter().enumerate() {
+                let (left, _) = cluster_bounds(&g.data, idx, c, g.attribs.direction);
+                for n in g.data.node_weights().filter(|n| n.cluster == Some(idx)) {
+                    assert!(left + c.margin(n.level, g.attribs.direction) <= n.pos.0);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn canvas_joins_lines_that_meet_or_cross() {
+        let mut canvas = Canvas::new(3, 3);
+        canvas.add(0, 0, UP | DOWN, Line::Solid);
+        canvas.add(0, 0, LEFT | RIGHT, Line::Solid);
+        canvas.add(0, 1, UP | DOWN, Line::Dashed);
+        canvas.add(0, 1, DOWN | RIGHT, Line::Dashed);
+        canvas.add(0, 2, LEFT | RIGHT, Line::Bold);
+        canvas.add(0, 2, UP | LEFT, Line::Dashed);
+        canvas.add(1, 0, UP | RIGHT, Line::Bold);
+        let glyphs = |canvas: &Canvas, style: Style| -> String {
+            (0..3).map(|x| canvas.glyph(0, x, style)).collect()
+        };
+        // Different kinds of lines meet with a solid junction
+        assert_eq!(glyphs(&canvas, Style::Unicode), "┼├┴");
+        assert_eq!(glyphs(&canvas, Style::Ascii), "+++");
+        assert_eq!(canvas.glyph(1, 0, Style::Unicode), '┗');
+        assert_eq!(canvas.glyph(2, 2, Style::Unicode), ' ');
+        // Side by side, the line up and right turns into one left and down
+        let canvas = canvas.orient(attrib::Direction::LeftRight);
+        assert_eq!(canvas.glyph(0, 1, Style::Unicode), '┓');
+    }
+
+    #[test]
+    fn records_can_have_empty_compartments() {
+        let g = lay_out("u: {|| login()}\na->u\n", "{}");
+        let record = g
+            .data
+            .node_weights()
+            .find_map(|n| n.record.as_ref())
+            .unwrap();
+        let compartments: Vec<&str> = record.cells.iter().map(|r| r[0].value.as_str()).collect();
+        assert_eq!(compartments, ["u", "", "login()"]);
+    }
+
+    #[test]
+    fn record_fields_go_on_lines_of_their_compartment() {
+        let g = lay_out("u: {name | age || login()}\na->u\n", "{}");
+        let record = g
+            .data
+            .node_weights()
+            .find_map(|n| n.record.as_ref())
+            .unwrap();
+        let compartments: Vec<&str> = record.cells.iter().map(|r| r[0].value.as_str()).collect();
+        assert_eq!(compartments, ["u", "name\nage", "login()"]);
+        assert_eq!(record.row_height(), [1, 2, 1]);
+    }
+
+    #[test]
+    fn records_render_in_both_styles() {
+        let script = "u: {name | age || login()}\na -> u\n";
+        assert_eq!(
+            render(&format!("[digraph]\n{}", script)),
+            [
+                "┌───┐       ",
+                "│ a │       ",
+                "└┬──┘       ",
+                "┌V────────┐ ",
+                "│    u    │ ",
+                "├─────────┤ ",
+                "│ name    │ ",
+                "│ age     │ ",
+                "├─────────┤ ",
+                "│ login() │ ",
+                "└─────────┘ ",
+            ]
+        );
+        assert_eq!(
+            render(&format!("[digraph] {{style: ascii}}\n{}", script)),
+            [
+                "+---+       ",
+                "| a |       ",
+                "++--+       ",
+                "+v--------+ ",
+                "|    u    | ",
+                "+---------+ ",
+                "| name    | ",
+                "| age     | ",
+                "+---------+ ",
+                "| login() | ",
+                "+---------+ ",
+            ]
+        );
+    }
+
+    #[test]
+    fn edges_meet_records_between_compartments_side_by_side() {
+        let script = "u: {name | age || login() | logout()}\na->u\nb->u\nc->u\nd->u\nu->e\nu->f\n";
+        for direction in ["lr", "rl"] {
+            let g = lay_out(script, &format!("{{direction: {}}}", direction));
+            let u = g
+                .data
+                .node_indices()
+                .find(|n| g.data[*n].record.is_some())
+                .unwrap();
+            assert_eq!(g.data[u].separators, [2, 5]);
+            // Edges end at the lower level in the first gap and leave u in the second
+            let ends = g.connections[0].iter().map(|con| match con {
+                Connection::Straight { from, .. } => from.0,
+                Connection::Bent { x2, .. } => *x2,
+            });
+            let starts = g.connections[1].iter().map(|con| con.start_x());
+            for x in ends.chain(starts) {
+                let offset = x - g.data[u].pos.0;
+                assert!(!g.data[u].separators.contains(&offset), "{}", direction);
+            }
+        }
+    }
+}
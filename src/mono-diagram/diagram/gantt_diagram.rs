@@ -1,12 +1,13 @@
 use std::{cmp::max, io::Write as _};
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use pest::Parser;
 use pest_derive::Parser;
 
 use crate::{
     attrib::{Attrib, Style},
-    utils::{pad_string_center, pad_string_left},
+    error::{ErrorKind, ScriptError},
+    utils::{display_width, pad_string_center, pad_string_left, unescape},
 };
 
 use super::Diagram;
@@ -24,13 +25,9 @@ impl Diagram for GanttDiagram {
     fn parse_from_str(&mut self, input: &str, attribs: Attrib) -> anyhow::Result<()> {
         let mut timeline_data = Vec::new();
         let mut period_data = Vec::new();
+        let mut period_spans = Vec::new();
         let diagram = GanttDiagramParser::parse(Rule::diagram, input)
-            .map_err(|e| {
-                Error::msg(format!(
-                    "parsing error: incorrect gantt grammar, context: {}",
-                    e.line()
-                ))
-            })?
+            .map_err(|e| ScriptError::from_pest(e, "gantt"))?
             .next()
             .unwrap();
         for line in diagram.into_inner() {
@@ -38,38 +35,55 @@ impl Diagram for GanttDiagram {
                 Rule::timeline => {
                     let timeline_inner = line.into_inner();
                     for time in timeline_inner {
-                        let time_string = time.as_str().to_string();
-                        self.time_width = max(self.time_width, time_string.len());
+                        let time_string = unescape(time.as_str());
+                        self.time_width = max(self.time_width, display_width(&time_string));
                         timeline_data.push(time_string);
                     }
                 }
                 Rule::period => {
+                    let span = line.as_span();
                     let mut period_inner = line.into_inner();
-                    let task = period_inner.next().unwrap().as_str().to_string();
-                    self.task_width = max(self.task_width, task.len());
-                    let start = period_inner
-                        .next()
-                        .unwrap()
-                        .as_str()
-                        .parse::<f32>()
-                        .map_err(|e| Error::msg(format!("parsing error: {}", e)))?;
-                    let end = period_inner
-                        .next()
-                        .unwrap()
-                        .as_str()
-                        .parse::<f32>()
-                        .map_err(|e| Error::msg(format!("parsing error: {}", e)))?;
+                    let task = unescape(period_inner.next().unwrap().as_str());
+                    self.task_width = max(self.task_width, display_width(&task));
+                    let start = period_inner.next().unwrap();
+                    let start = start.as_str().parse::<f32>().map_err(|e| {
+                        ScriptError::new(ErrorKind::Parsing, e.to_string())
+                            .with_span(start.as_span())
+                    })?;
+                    let end = period_inner.next().unwrap();
+                    let end = end.as_str().parse::<f32>().map_err(|e| {
+                        ScriptError::new(ErrorKind::Parsing, e.to_string()).with_span(end.as_span())
+                    })?;
                     if start >= end {
-                        return Err(Error::msg(format!("diagram error: task ending time must be larger than starting time: task: {}, start: {}, end: {}", task, start, end)));
+                        return Err(ScriptError::new(
+                            ErrorKind::Diagram,
+                            format!(
+                                "task ending time must be larger than starting time: task: {}, start: {}, end: {}",
+                                task, start, end
+                            ),
+                        )
+                        .with_span(span)
+                        .into());
                     }
                     period_data.push((task, start, end));
+                    period_spans.push(span);
                 }
                 _ => (),
             }
         }
-        for (task, _, ed) in period_data.iter() {
+        for ((task, _, ed), span) in period_data.iter().zip(period_spans) {
             if *ed > timeline_data.len() as f32 {
-                return Err(Error::msg(format!("diagram error: task ending time exceeds timeline: task: {}, end: {}, timeline length: {}", task, ed, timeline_data.len())));
+                return Err(ScriptError::new(
+                    ErrorKind::Diagram,
+                    format!(
+                        "task ending time exceeds timeline: task: {}, end: {}, timeline length: {}",
+                        task,
+                        ed,
+                        timeline_data.len()
+                    ),
+                )
+                .with_span(span)
+                .into());
             }
         }
         self.timeline = timeline_data;
@@ -1,13 +1,14 @@
-use std::{collections::HashMap, io::Write as _, iter::repeat};
+use std::{collections::HashMap, io::Write as _, iter::repeat_n};
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use pest::Parser;
 use pest_derive::Parser;
 
 use crate::{
     attrib::{Attrib, Style},
     data_structure::table::{Table, TableCell},
-    utils::pad_string_center,
+    error::{ErrorKind, ScriptError},
+    utils::{display_width, pad_string_center, unescape},
 };
 
 use super::Diagram;
@@ -23,16 +24,11 @@ pub struct GridDiagram {
 impl Diagram for GridDiagram {
     fn parse_from_str(&mut self, input: &str, attribs: Attrib) -> Result<()> {
         let diagram = GridDiagrmParser::parse(Rule::diagram, input)
-            .map_err(|e| {
-                Error::msg(format!(
-                    "parsing error: incorrect grid grammar, context: {}",
-                    e.line()
-                ))
-            })?
+            .map_err(|e| ScriptError::from_pest(e, "grid"))?
             .next()
             .unwrap();
         let mut grid_data: Table<String> = Table::default();
-        let mut assign_map: HashMap<(usize, usize), &str> = HashMap::new();
+        let mut assign_map: HashMap<(usize, usize), String> = HashMap::new();
         for ele in diagram.into_inner() {
             match ele.as_rule() {
                 Rule::width => {
@@ -42,21 +38,28 @@ impl Diagram for GridDiagram {
                     grid_data.height = ele.into_inner().next().unwrap().as_str().parse().unwrap();
                 }
                 Rule::assign => {
+                    let span = ele.as_span();
                     let mut assign_inner = ele.into_inner();
                     let pos = assign_inner.next().unwrap();
                     let mut pos_inner = pos.into_inner();
                     let x = pos_inner.next().unwrap().as_str().parse().unwrap();
                     let y = pos_inner.next().unwrap().as_str().parse().unwrap();
-                    let cell = assign_inner.next().unwrap().as_str();
-                    if cell.len() > MAX_CELL_WIDTH {
-                        return Err(Error::msg(format!(
-                            "diagram error: length of text in a grid cell should be less than {MAX_CELL_WIDTH}, context: {x},{y}:{cell}"
-                        )));
+                    let cell = unescape(assign_inner.next().unwrap().as_str());
+                    if display_width(&cell) > MAX_CELL_WIDTH {
+                        return Err(ScriptError::new(
+                            ErrorKind::Diagram,
+                            format!("length of text in a grid cell should be less than {MAX_CELL_WIDTH}"),
+                        )
+                        .with_span(span)
+                        .into());
                     }
                     if assign_map.contains_key(&(x, y)) {
-                        return Err(Error::msg(format!(
-                            "diagram error: assign a cell for multiple times, context: {x},{y}:{cell}"
-                        )));
+                        return Err(ScriptError::new(
+                            ErrorKind::Diagram,
+                            "assign a cell for multiple times",
+                        )
+                        .with_span(span)
+                        .into());
                     }
                     assign_map.insert((x, y), cell);
                 }
@@ -64,16 +67,18 @@ impl Diagram for GridDiagram {
             }
         }
         if grid_data.width == 0 || grid_data.height == 0 {
-            return Err(Error::msg(
-                "diagram error: please specify the width and height of the grid",
-            ));
+            return Err(ScriptError::new(
+                ErrorKind::Diagram,
+                "please specify the width and height of the grid",
+            )
+            .into());
         }
         for j in 1..=(grid_data.height) {
             let mut row: Vec<TableCell<String>> = Vec::new();
             for i in 1..=(grid_data.width) {
                 if assign_map.contains_key(&(i, j)) {
                     row.push(TableCell {
-                        value: assign_map[&(i, j)].to_string(),
+                        value: assign_map[&(i, j)].clone(),
                     });
                 } else {
                     row.push(TableCell {
@@ -97,35 +102,35 @@ impl Diagram for GridDiagram {
         };
 
         let separating_line = (0..self.data.width - 1).fold(
-            repeat(palette[4]).take(MAX_CELL_WIDTH).collect::<String>(),
+            repeat_n(palette[4], MAX_CELL_WIDTH).collect::<String>(),
             |acc, _| {
                 format!(
                     "{}{}{}",
                     acc,
                     palette[10],
-                    repeat(palette[4]).take(MAX_CELL_WIDTH).collect::<String>(),
+                    repeat_n(palette[4], MAX_CELL_WIDTH).collect::<String>(),
                 )
             },
         );
         let first_line = (0..self.data.width - 1).fold(
-            repeat(palette[4]).take(MAX_CELL_WIDTH).collect::<String>(),
+            repeat_n(palette[4], MAX_CELL_WIDTH).collect::<String>(),
             |acc, _| {
                 format!(
                     "{}{}{}",
                     acc,
                     palette[6],
-                    repeat(palette[4]).take(MAX_CELL_WIDTH).collect::<String>(),
+                    repeat_n(palette[4], MAX_CELL_WIDTH).collect::<String>(),
                 )
             },
         );
         let last_line = (0..self.data.width - 1).fold(
-            repeat(palette[4]).take(MAX_CELL_WIDTH).collect::<String>(),
+            repeat_n(palette[4], MAX_CELL_WIDTH).collect::<String>(),
             |acc, _| {
                 format!(
                     "{}{}{}",
                     acc,
                     palette[7],
-                    repeat(palette[4]).take(MAX_CELL_WIDTH).collect::<String>(),
+                    repeat_n(palette[4], MAX_CELL_WIDTH).collect::<String>(),
                 )
             },
         );
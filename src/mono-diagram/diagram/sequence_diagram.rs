@@ -0,0 +1,761 @@
+use std::{cmp::max, collections::HashMap};
+
+use pest::{iterators::Pair, Parser, Span};
+use pest_derive::Parser;
+
+use crate::{
+    attrib::{Attrib, Style},
+    error::{ErrorKind, ScriptError},
+    utils::{display_width, unescape},
+};
+
+use super::Diagram;
+
+// Box corners and edges, lifeline and where it leaves a box, activation bar, crossing of a
+// lifeline, dashed line, and the junctions of a fragment edge with a line across
+const PALETTE_ASCII: [char; 13] = [
+    '+', '+', '+', '+', '-', '|', '|', '+', '#', '+', '.', '+', '+',
+];
+const PALETTE_UNICODE: [char; 13] = [
+    '┌', '┐', '└', '┘', '─', '│', '│', '┬', '┃', '┼', '┄', '├', '┤',
+];
+// Arrowheads pointing right and left, for sync, async and reply messages
+const HEADS_ASCII: [(&str, &str); 3] = [(">", "<"), (">>", "<<"), (">", "<")];
+const HEADS_UNICODE: [(&str, &str); 3] = [("▶", "◀"), (">", "<"), (">", "<")];
+
+#[derive(Debug, Default)]
+pub struct SequenceDiagram {
+    participants: Vec<String>,
+    events: Vec<Event>,
+    /// Deepest nesting of fragments
+    depth: usize,
+    attribs: Attrib,
+}
+
+/// Kind of a message, which is drawn with its own line and arrowhead
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Arrow {
+    Sync,
+    Async,
+    Reply,
+}
+
+/// Where a note goes, beside a participant or over the participants from one to another
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Note {
+    Left(usize),
+    Right(usize),
+    Over(usize, usize),
+}
+
+#[derive(Debug)]
+enum Event {
+    Message {
+        from: usize,
+        to: usize,
+        arrow: Arrow,
+        text: String,
+    },
+    Note {
+        place: Note,
+        lines: Vec<String>,
+    },
+    Activate(usize),
+    Deactivate(usize),
+    Fragment {
+        depth: usize,
+        title: String,
+    },
+    Else {
+        depth: usize,
+        title: String,
+    },
+    End {
+        depth: usize,
+    },
+}
+
+impl Event {
+    /// Rows the event takes, not counting the blank row above it
+    fn rows(&self) -> usize {
+        match self {
+            Event::Message { from, to, text, .. } => {
+                if from == to || !text.is_empty() {
+                    2
+                } else {
+                    1
+                }
+            }
+            Event::Note { lines, .. } => lines.len() + 2,
+            Event::Activate(_) | Event::Deactivate(_) => 0,
+            Event::Fragment { .. } | Event::Else { .. } | Event::End { .. } => 1,
+        }
+    }
+}
+
+impl Diagram for SequenceDiagram {
+    fn parse_from_str(&mut self, input: &str, attribs: Attrib) -> anyhow::Result<()> {
+        let diagram = SequenceDiagramParser::parse(Rule::diagram, input)
+            .map_err(|e| ScriptError::from_pest(e, "sequence"))?
+            .next()
+            .unwrap();
+        let mut names: HashMap<&str, usize> = HashMap::new();
+        let mut declared: Vec<&str> = Vec::new();
+        let mut active: HashMap<usize, usize> = HashMap::new();
+        // Fragments not closed yet, and whether they are `alt`
+        let mut open: Vec<(Span, bool)> = Vec::new();
+        self.participants = Vec::new();
+        self.events = Vec::new();
+        self.depth = 0;
+        for line in diagram.into_inner() {
+            let span = line.as_span();
+            match line.as_rule() {
+                Rule::participant => {
+                    let mut inner = line.into_inner();
+                    let name = inner.next().unwrap();
+                    if declared.contains(&name.as_str()) {
+                        return Err(ScriptError::new(
+                            ErrorKind::Diagram,
+                            format!("participant is defined more than once: {}", name.as_str()),
+                        )
+                        .with_span(name.as_span())
+                        .into());
+                    }
+                    declared.push(name.as_str());
+                    let i = self.participant(&mut names, name);
+                    if let Some(label) = inner.next() {
+                        self.participants[i] = unescape(label.as_str());
+                    }
+                }
+                Rule::message => {
+                    let mut inner = line.into_inner();
+                    let from = self.participant(&mut names, inner.next().unwrap());
+                    let arrow = match inner.next().unwrap().as_str() {
+                        "->>" => Arrow::Async,
+                        "-->" => Arrow::Reply,
+                        _ => Arrow::Sync,
+                    };
+                    let to = self.participant(&mut names, inner.next().unwrap());
+                    let text = inner.next().map_or(String::new(), |t| unescape(t.as_str()));
+                    self.events.push(Event::Message {
+                        from,
+                        to,
+                        arrow,
+                        text,
+                    });
+                }
+                Rule::activate => {
+                    let p = self.participant(&mut names, line.into_inner().next().unwrap());
+                    *active.entry(p).or_default() += 1;
+                    self.events.push(Event::Activate(p));
+                }
+                Rule::deactivate => {
+                    let name = line.into_inner().next().unwrap();
+                    let p = self.participant(&mut names, name.clone());
+                    match active.get_mut(&p) {
+                        Some(count) if *count > 0 => *count -= 1,
+                        _ => {
+                            return Err(ScriptError::new(
+                                ErrorKind::Diagram,
+                                format!("participant is not active: {}", name.as_str()),
+                            )
+                            .with_span(name.as_span())
+                            .into())
+                        }
+                    }
+                    self.events.push(Event::Deactivate(p));
+                }
+                Rule::note => {
+                    let mut inner = line.into_inner().peekable();
+                    let side = inner
+                        .next_if(|p| p.as_rule() == Rule::note_side)
+                        .map(|p| p.as_str().to_ascii_lowercase());
+                    let a = self.participant(&mut names, inner.next().unwrap());
+                    let place = match side.as_deref() {
+                        Some("left") => Note::Left(a),
+                        Some(_) => Note::Right(a),
+                        None => match inner.next_if(|p| p.as_rule() == Rule::name) {
+                            Some(name) => {
+                                let b = self.participant(&mut names, name);
+                                Note::Over(a.min(b), a.max(b))
+                            }
+                            None => Note::Over(a, a),
+                        },
+                    };
+                    let text = unescape(inner.next().unwrap().as_str());
+                    self.events.push(Event::Note {
+                        place,
+                        lines: text.split("\\n").map(|l| l.to_string()).collect(),
+                    });
+                }
+                Rule::fragment => {
+                    let mut inner = line.into_inner();
+                    let kind = inner.next().unwrap().as_str().to_ascii_lowercase();
+                    let title = match inner.next() {
+                        Some(text) => format!("{} [{}]", kind, unescape(text.as_str())),
+                        None => kind.clone(),
+                    };
+                    self.events.push(Event::Fragment {
+                        depth: open.len(),
+                        title,
+                    });
+                    open.push((span, kind == "alt"));
+                    self.depth = max(self.depth, open.len());
+                }
+                Rule::else_branch => {
+                    if !open.last().is_some_and(|(_, alt)| *alt) {
+                        return Err(ScriptError::new(
+                            ErrorKind::Diagram,
+                            "else can only split an alt fragment",
+                        )
+                        .with_span(span)
+                        .into());
+                    }
+                    let title = line
+                        .into_inner()
+                        .next()
+                        .map_or(String::new(), |t| format!("[{}]", unescape(t.as_str())));
+                    self.events.push(Event::Else {
+                        depth: open.len() - 1,
+                        title,
+                    });
+                }
+                Rule::end => {
+                    if open.pop().is_none() {
+                        return Err(ScriptError::new(
+                            ErrorKind::Diagram,
+                            "end has no fragment to close",
+                        )
+                        .with_span(span)
+                        .into());
+                    }
+                    self.events.push(Event::End { depth: open.len() });
+                }
+                _ => (),
+            }
+        }
+        if let Some((span, _)) = open.pop() {
+            return Err(
+                ScriptError::new(ErrorKind::Diagram, "fragment is not closed with end")
+                    .with_span(span)
+                    .into(),
+            );
+        }
+        self.attribs = attribs;
+        Ok(())
+    }
+
+    fn write(&self) -> anyhow::Result<Vec<u8>> {
+        let (palette, heads) = match self.attribs.style {
+            Style::Ascii => (PALETTE_ASCII, HEADS_ASCII),
+            Style::Unicode => (PALETTE_UNICODE, HEADS_UNICODE),
+        };
+        let xs = self.columns();
+        let box_width: Vec<usize> = self
+            .participants
+            .iter()
+            .map(|p| display_width(p) + 4)
+            .collect();
+        let note_width =
+            |lines: &[String]| lines.iter().map(|l| display_width(l)).max().unwrap_or(0) + 4;
+        let note_left = |place: Note, width: usize| match place {
+            Note::Left(a) => xs[a] - 1 - width,
+            Note::Right(a) => xs[a] + 2,
+            Note::Over(a, b) if a == b => xs[a] - width / 2,
+            Note::Over(a, _) => xs[a] - 2,
+        };
+
+        // Fragments are drawn outside everything else, with room for their titles
+        let mut right = 0;
+        for (i, x) in xs.iter().enumerate() {
+            right = max(right, x + box_width[i] - box_width[i] / 2);
+        }
+        for e in self.events.iter() {
+            right = max(
+                right,
+                match e {
+                    Event::Message { from, to, text, .. } if from == to => {
+                        xs[*from] + 5 + display_width(text)
+                    }
+                    Event::Note { place, lines } => {
+                        let width = match place {
+                            Note::Over(a, b) if a != b => {
+                                max(note_width(lines), xs[*b] - xs[*a] + 5)
+                            }
+                            _ => note_width(lines),
+                        };
+                        note_left(*place, width) + width
+                    }
+                    Event::Fragment { depth, title } | Event::Else { depth, title } => {
+                        (display_width(title) + 5 + 4 * depth).saturating_sub(2 * self.depth)
+                    }
+                    _ => 0,
+                },
+            );
+        }
+        let frame = |depth: usize| (2 * depth, right + 1 + 2 * (self.depth - 1 - depth));
+        let width = right + 2 * self.depth;
+        let height = 3
+            + self
+                .events
+                .iter()
+                .map(|e| if e.rows() > 0 { e.rows() + 1 } else { 0 })
+                .sum::<usize>()
+            + 1;
+
+        let mut buffer = vec![vec![' '; width]; height];
+        let mut texts: HashMap<(usize, usize), &str> = HashMap::new();
+
+        // Participants, with their lifelines running down to the bottom
+        for (i, p) in self.participants.iter().enumerate() {
+            let left = xs[i] - box_width[i] / 2;
+            draw_box(&mut buffer, &palette, (0, left), (3, box_width[i]));
+            buffer[2][xs[i]] = palette[7];
+            for row in buffer.iter_mut().skip(3) {
+                row[xs[i]] = palette[6];
+            }
+            write_text(&mut buffer, &mut texts, 1, left + 2, p);
+        }
+
+        // Activations run from the message before them to the one before they end
+        let mut y = 3;
+        let mut starts: Vec<Vec<usize>> = vec![Vec::new(); self.participants.len()];
+        for e in self.events.iter() {
+            match e {
+                Event::Activate(p) => starts[*p].push(max(y - 1, 3)),
+                Event::Deactivate(p) => {
+                    let start = starts[*p].pop().unwrap();
+                    for row in buffer.iter_mut().take(y).skip(start) {
+                        row[xs[*p]] = palette[8];
+                    }
+                }
+                _ if e.rows() > 0 => y += e.rows() + 1,
+                _ => (),
+            }
+        }
+        for (p, s) in starts.iter().enumerate() {
+            for &start in s {
+                for row in buffer.iter_mut().skip(start) {
+                    row[xs[p]] = palette[8];
+                }
+            }
+        }
+
+        let mut y = 3;
+        let mut opened: Vec<usize> = Vec::new();
+        for e in self.events.iter() {
+            if e.rows() == 0 {
+                continue;
+            }
+            y += 1;
+            match e {
+                Event::Message {
+                    from,
+                    to,
+                    arrow,
+                    text,
+                } => {
+                    let line = if *arrow == Arrow::Reply {
+                        palette[10]
+                    } else {
+                        palette[4]
+                    };
+                    let (head_right, head_left) = heads[*arrow as usize];
+                    let x = xs[*from];
+                    if from == to {
+                        // A message to itself loops out to the right and back
+                        buffer[y][(x + 1)..(x + 3)].fill(line);
+                        buffer[y][x + 3] = palette[1];
+                        buffer[y + 1][(x + 1)..(x + 3)].fill(line);
+                        buffer[y + 1][x + 3] = palette[3];
+                        put(&mut buffer, y + 1, x + 1, head_left);
+                        if !text.is_empty() {
+                            write_text(&mut buffer, &mut texts, y, x + 5, text);
+                        }
+                    } else {
+                        let (a, b) = (x.min(xs[*to]), x.max(xs[*to]));
+                        if !text.is_empty() {
+                            write_text(&mut buffer, &mut texts, y, a + 2, text);
+                            y += 1;
+                        }
+                        buffer[y][(a + 1)..b].fill(line);
+                        if xs[*to] > x {
+                            put(&mut buffer, y, b - display_width(head_right), head_right);
+                        } else {
+                            put(&mut buffer, y, a + 1, head_left);
+                        }
+                    }
+                }
+                Event::Note { place, lines } => {
+                    let width = match place {
+                        Note::Over(a, b) if a != b => max(note_width(lines), xs[*b] - xs[*a] + 5),
+                        _ => note_width(lines),
+                    };
+                    let left = note_left(*place, width);
+                    draw_box(&mut buffer, &palette, (y, left), (lines.len() + 2, width));
+                    for (i, line) in lines.iter().enumerate() {
+                        write_text(&mut buffer, &mut texts, y + 1 + i, left + 2, line);
+                    }
+                }
+                Event::Fragment { depth, title } => {
+                    let (left, right) = frame(*depth);
+                    draw_edge(
+                        &mut buffer,
+                        &palette,
+                        y,
+                        (left, right),
+                        (palette[0], palette[1]),
+                    );
+                    write_text(&mut buffer, &mut texts, y, left + 2, title);
+                    opened.push(y);
+                }
+                Event::Else { depth, title } => {
+                    let (left, right) = frame(*depth);
+                    buffer[y][left] = palette[11];
+                    buffer[y][right] = palette[12];
+                    for c in buffer[y][(left + 1)..right].iter_mut() {
+                        if *c == ' ' {
+                            *c = palette[10];
+                        }
+                    }
+                    if !title.is_empty() {
+                        write_text(&mut buffer, &mut texts, y, left + 2, title);
+                    }
+                }
+                Event::End { depth } => {
+                    let (left, right) = frame(*depth);
+                    draw_edge(
+                        &mut buffer,
+                        &palette,
+                        y,
+                        (left, right),
+                        (palette[2], palette[3]),
+                    );
+                    for row in buffer.iter_mut().take(y).skip(opened.pop().unwrap() + 1) {
+                        for x in [left, right] {
+                            if row[x] == ' ' {
+                                row[x] = palette[5];
+                            }
+                        }
+                    }
+                }
+                _ => (),
+            }
+            y += match e {
+                // The label row of a message between two lifelines is passed already
+                Event::Message { from, to, .. } if from != to => 1,
+                _ => e.rows(),
+            };
+        }
+
+        let mut res = Vec::new();
+        for (y, row) in buffer.iter().enumerate() {
+            let mut line = String::new();
+            for (x, c) in row.iter().enumerate() {
+                if let Some(text) = texts.get(&(y, x)) {
+                    line.push_str(text);
+                } else if *c != '\0' {
+                    line.push(*c);
+                }
+            }
+            res.extend_from_slice(line.trim_end().as_bytes());
+            res.push(b'\n');
+        }
+        Ok(res)
+    }
+}
+
+impl SequenceDiagram {
+    /// Index of a participant, which is added the first time it is named
+    fn participant<'a>(
+        &mut self,
+        names: &mut HashMap<&'a str, usize>,
+        name: Pair<'a, Rule>,
+    ) -> usize {
+        *names.entry(name.as_str()).or_insert_with(|| {
+            self.participants.push(name.as_str().to_string());
+            self.participants.len() - 1
+        })
+    }
+
+    /// Column of the lifeline of each participant
+    ///
+    /// Each participant goes as far left as it can, leaving room for the boxes, messages and
+    /// notes between it and the participants before it.
+    fn columns(&self) -> Vec<usize> {
+        let n = self.participants.len();
+        let margin = 2 * self.depth;
+        let box_width: Vec<usize> = self
+            .participants
+            .iter()
+            .map(|p| display_width(p) + 4)
+            .collect();
+        let mut lower: Vec<usize> = box_width.iter().map(|w| margin + w / 2).collect();
+        // Smallest distance from the first lifeline of each pair to the second
+        let mut gaps: Vec<(usize, usize, usize)> = (1..n)
+            .map(|i| {
+                (
+                    i - 1,
+                    i,
+                    box_width[i - 1] - box_width[i - 1] / 2 + box_width[i] / 2 + 2,
+                )
+            })
+            .collect();
+        for e in self.events.iter() {
+            match e {
+                Event::Message { from, to, text, .. } if from == to && from + 1 < n => {
+                    gaps.push((*from, from + 1, display_width(text) + 7));
+                }
+                Event::Message { from, to, text, .. } if from != to => {
+                    gaps.push((*from.min(to), *from.max(to), display_width(text) + 4));
+                }
+                Event::Note { place, lines } => {
+                    let width = lines.iter().map(|l| display_width(l)).max().unwrap_or(0) + 4;
+                    match *place {
+                        Note::Left(a) if a == 0 => lower[a] = max(lower[a], margin + width + 1),
+                        Note::Left(a) => gaps.push((a - 1, a, width + 3)),
+                        Note::Right(a) if a + 1 < n => gaps.push((a, a + 1, width + 3)),
+                        Note::Over(a, b) if a == b => {
+                            if a == 0 {
+                                lower[a] = max(lower[a], margin + width / 2);
+                            } else {
+                                gaps.push((a - 1, a, width / 2 + 2));
+                            }
+                            if a + 1 < n {
+                                gaps.push((a, a + 1, width - width / 2 + 2));
+                            }
+                        }
+                        Note::Over(a, b) => {
+                            lower[a] = max(lower[a], margin + 2);
+                            gaps.push((a, b, width.saturating_sub(5)));
+                        }
+                        _ => (),
+                    }
+                }
+                _ => (),
+            }
+        }
+        let mut xs = lower;
+        for j in 0..n {
+            for &(i, _, d) in gaps.iter().filter(|g| g.1 == j) {
+                xs[j] = max(xs[j], xs[i] + d);
+            }
+        }
+        xs
+    }
+}
+
+/// Draw a box with its top left corner at `(y, x)`
+fn draw_box(
+    buffer: &mut [Vec<char>],
+    palette: &[char],
+    (y, x): (usize, usize),
+    (h, w): (usize, usize),
+) {
+    for (i, row) in buffer.iter_mut().skip(y).take(h).enumerate() {
+        let (left, right) = if i == 0 {
+            (palette[0], palette[1])
+        } else if i == h - 1 {
+            (palette[2], palette[3])
+        } else {
+            (palette[5], palette[5])
+        };
+        let fill = if i == 0 || i == h - 1 {
+            palette[4]
+        } else {
+            ' '
+        };
+        row[x] = left;
+        row[(x + 1)..(x + w - 1)].fill(fill);
+        row[x + w - 1] = right;
+    }
+}
+
+/// Draw the top or bottom edge of a fragment, crossing the lifelines on its way
+fn draw_edge(
+    buffer: &mut [Vec<char>],
+    palette: &[char],
+    y: usize,
+    (left, right): (usize, usize),
+    (start, end): (char, char),
+) {
+    buffer[y][left] = start;
+    buffer[y][right] = end;
+    for c in buffer[y][(left + 1)..right].iter_mut() {
+        *c = if *c == palette[6] || *c == palette[8] {
+            palette[9]
+        } else {
+            palette[4]
+        };
+    }
+}
+
+/// Write text as a whole at its position, over cells marked with '\0'
+fn write_text<'a>(
+    buffer: &mut [Vec<char>],
+    texts: &mut HashMap<(usize, usize), &'a str>,
+    y: usize,
+    x: usize,
+    text: &'a str,
+) {
+    buffer[y][x..(x + display_width(text))].fill('\0');
+    texts.insert((y, x), text);
+}
+
+/// Put a string of one column characters in the buffer
+fn put(buffer: &mut [Vec<char>], y: usize, x: usize, s: &str) {
+    for (i, c) in s.chars().enumerate() {
+        buffer[y][x + i] = c;
+    }
+}
+
+#[derive(Parser)]
+#[grammar = "mono-diagram/grammar/sequence.pest"]
+struct SequenceDiagramParser;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(script: &str) -> anyhow::Result<SequenceDiagram> {
+        let mut diagram = SequenceDiagram::default();
+        diagram.parse_from_str(script, Attrib::default())?;
+        Ok(diagram)
+    }
+
+    fn render(script: &str, style: Style) -> Vec<String> {
+        let mut diagram = SequenceDiagram::default();
+        let attribs = Attrib {
+            style,
+            ..Default::default()
+        };
+        diagram.parse_from_str(script, attribs).unwrap();
+        String::from_utf8(diagram.write().unwrap())
+            .unwrap()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn participants_go_in_the_order_they_are_named() {
+        let d = parse("b -> a: hi\nparticipant c: Cache\nnote left of d: x\n").unwrap();
+        assert_eq!(d.participants, ["b", "a", "Cache", "d"]);
+        assert!(matches!(
+            d.events[0],
+            Event::Message {
+                from: 0,
+                to: 1,
+                arrow: Arrow::Sync,
+                ..
+            }
+        ));
+        assert!(matches!(
+            d.events[1],
+            Event::Note {
+                place: Note::Left(3),
+                ..
+            }
+        ));
+    }
+
+    #[test]
+    fn lifelines_leave_room_for_messages_and_notes() {
+        let d = parse("a -> b: a long message\nnote over c: a wide note here\nb -> c\n").unwrap();
+        let xs = d.columns();
+        assert!(xs[1] - xs[0] >= display_width("a long message") + 4);
+        // The note is centered on c, and stays clear of b
+        assert!(xs[2] - xs[1] >= display_width("a wide note here") / 2 + 4);
+        let d = parse("alt\nalt\na -> b\nend\nend\n").unwrap();
+        assert_eq!(d.depth, 2);
+        assert_eq!(d.columns()[0], 4 + 2);
+    }
+
+    #[test]
+    fn fragments_and_activations_must_match() {
+        for (script, message) in [
+            ("a -> b\nend\n", "end has no fragment to close"),
+            ("loop\nelse\nend\n", "else can only split an alt fragment"),
+            ("opt\na -> b\n", "fragment is not closed with end"),
+            (
+                "activate a\ndeactivate a\ndeactivate a\n",
+                "participant is not active: a",
+            ),
+            (
+                "participant a\nparticipant a\n",
+                "participant is defined more than once: a",
+            ),
+        ] {
+            let e = parse(script).unwrap_err();
+            assert_eq!(e.downcast::<ScriptError>().unwrap().message, message);
+        }
+    }
+
+    #[test]
+    fn sequence_renders_in_both_styles() {
+        let script = "participant u: User\nu -> s: login\nactivate s\nalt ok\ns --> u: token\n\
+            else\ns ->> u: retry\nend\nnote over u, s: done\ndeactivate s\ns -> s: log\n";
+        assert_eq!(
+            render(script, Style::Unicode),
+            [
+                "  ┌──────┐   ┌───┐",
+                "  │ User │   │ s │",
+                "  └───┬──┘   └─┬─┘",
+                "      │        │",
+                "      │ login  │",
+                "      │───────▶┃",
+                "      │        ┃",
+                "┌─alt [ok]─────┼────────┐",
+                "│     │        ┃        │",
+                "│     │ token  ┃        │",
+                "│     │<┄┄┄┄┄┄┄┃        │",
+                "│     │        ┃        │",
+                "├┄┄┄┄┄│┄┄┄┄┄┄┄┄┃┄┄┄┄┄┄┄┄┤",
+                "│     │        ┃        │",
+                "│     │ retry  ┃        │",
+                "│     │<───────┃        │",
+                "│     │        ┃        │",
+                "└─────┼────────┼────────┘",
+                "      │        ┃",
+                "    ┌────────────┐",
+                "    │ done       │",
+                "    └────────────┘",
+                "      │        │",
+                "      │        │──┐ log",
+                "      │        │◀─┘",
+                "      │        │",
+            ]
+        );
+        assert_eq!(
+            render(script, Style::Ascii),
+            [
+                "  +------+   +---+",
+                "  | User |   | s |",
+                "  +---+--+   +-+-+",
+                "      |        |",
+                "      | login  |",
+                "      |------->#",
+                "      |        #",
+                "+-alt [ok]-----+--------+",
+                "|     |        #        |",
+                "|     | token  #        |",
+                "|     |<.......#        |",
+                "|     |        #        |",
+                "+.....|........#........+",
+                "|     |        #        |",
+                "|     | retry  #        |",
+                "|     |<<------#        |",
+                "|     |        #        |",
+                "+-----+--------+--------+",
+                "      |        #",
+                "    +------------+",
+                "    | done       |",
+                "    +------------+",
+                "      |        |",
+                "      |        |--+ log",
+                "      |        |<-+",
+                "      |        |",
+            ]
+        );
+    }
+}
@@ -1,13 +1,14 @@
-use std::{io::Write as _, iter::repeat};
+use std::{io::Write as _, iter::repeat_n};
 
-use anyhow::{Error, Result};
+use anyhow::Result;
 use pest::Parser;
 use pest_derive::Parser;
 
 use crate::{
     attrib::{Attrib, Style},
     data_structure::table::{Table, TableCell},
-    utils::pad_string_right,
+    error::ScriptError,
+    utils::{pad_string_center, pad_string_right, unescape},
 };
 
 use super::Diagram;
@@ -22,12 +23,7 @@ impl Diagram for TableDiagram {
     fn parse_from_str(&mut self, input: &str, attribs: Attrib) -> Result<()> {
         let mut table_data = Table::<String>::default();
         let diagram = TableDiagramParser::parse(Rule::diagram, input)
-            .map_err(|e| {
-                Error::msg(format!(
-                    "parsing error: incorrect table grammar, context: {}",
-                    e.line()
-                ))
-            })?
+            .map_err(|e| ScriptError::from_pest(e, "table"))?
             .next()
             .unwrap();
         let mut width: usize = 0;
@@ -37,7 +33,7 @@ impl Diagram for TableDiagram {
                 Rule::line => {
                     for cell in line.into_inner() {
                         row.push(TableCell {
-                            value: cell.as_str().to_owned(),
+                            value: unescape(cell.as_str()),
                         });
                     }
                     table_data.height = idx + 1;
@@ -58,85 +54,115 @@ impl Diagram for TableDiagram {
     }
 
     fn write(&self) -> Result<Vec<u8>> {
-        const PALETTE_ASCII: [char; 11] = ['+', '+', '+', '+', '-', '|', '+', '+', '+', '+', '+'];
-        const PALETTE_UNICODE: [char; 11] = ['┌', '┐', '└', '┘', '─', '│', '┬', '┴', '├', '┤', '┼'];
-        let palette = match self.attribs.style {
-            Style::Ascii => PALETTE_ASCII,
-            Style::Unicode => PALETTE_UNICODE,
-        };
-
-        let mut col_width: Vec<usize> = repeat(0).take(self.data.width).collect();
-        for row in self.data.cells.iter() {
-            for (idx, col) in row.iter().enumerate() {
-                let cur_width = col.value.as_str().len();
-                if cur_width > col_width[idx] {
-                    col_width[idx] = cur_width;
-                }
-            }
+        let mut buffer = Vec::new();
+        for line in draw_table(
+            &self.data,
+            &self.data.col_width(),
+            self.attribs.style,
+            false,
+        ) {
+            writeln!(&mut buffer, "{}", line)?;
         }
-        let mut separating_line: String =
-            col_width.iter().fold(palette[8].to_string(), |acc, &w| {
-                format!(
-                    "{}{}{}",
-                    acc,
-                    repeat(palette[4]).take(w + 2).collect::<String>(),
-                    palette[10]
-                )
-            });
-        separating_line.pop();
-        separating_line.push(palette[9]);
-        let mut first_line: String = col_width.iter().fold(palette[0].to_string(), |acc, &w| {
-            format!(
-                "{}{}{}",
-                acc,
-                repeat(palette[4]).take(w + 2).collect::<String>(),
-                palette[6]
-            )
-        });
-        first_line.pop();
-        first_line.push(palette[1]);
-        let mut last_line: String = col_width.iter().fold(palette[2].to_string(), |acc, &w| {
+        Ok(buffer)
+    }
+}
+
+/// Draw the lines of a table, each column as wide as in `col_width`, with the text of the
+/// first row in the middle of its cells if it is a `header`
+///
+/// Cells can have more than one line, and a row takes as many lines as its tallest cell.
+pub(super) fn draw_table(
+    table: &Table<String>,
+    col_width: &[usize],
+    style: Style,
+    header: bool,
+) -> Vec<String> {
+    const PALETTE_ASCII: [char; 11] = ['+', '+', '+', '+', '-', '|', '+', '+', '+', '+', '+'];
+    const PALETTE_UNICODE: [char; 11] = ['┌', '┐', '└', '┘', '─', '│', '┬', '┴', '├', '┤', '┼'];
+    let palette = match style {
+        Style::Ascii => PALETTE_ASCII,
+        Style::Unicode => PALETTE_UNICODE,
+    };
+
+    let border = |left: char, cross: char, right: char| {
+        let mut line: String = col_width.iter().fold(left.to_string(), |acc, &w| {
             format!(
                 "{}{}{}",
                 acc,
-                repeat(palette[4]).take(w + 2).collect::<String>(),
-                palette[7]
+                repeat_n(palette[4], w + 2).collect::<String>(),
+                cross
             )
         });
-        last_line.pop();
-        last_line.push(palette[3]);
+        line.pop();
+        line.push(right);
+        line
+    };
+    let separating_line = border(palette[8], palette[10], palette[9]);
 
-        let mut buffer = Vec::new();
-        for (idx, row) in self.data.cells.iter().enumerate() {
+    let mut lines = vec![border(palette[0], palette[6], palette[1])];
+    for (idx, (row, height)) in table.cells.iter().zip(table.row_height()).enumerate() {
+        if idx > 0 {
+            lines.push(separating_line.clone());
+        }
+        for i in 0..height {
             let text_line: String =
                 col_width
                     .iter()
                     .enumerate()
-                    .fold(palette[5].to_string(), |acc, (idx, &w)| {
-                        let text_with_space = if idx < row.len() {
-                            " ".to_string() + &row[idx].value
+                    .fold(palette[5].to_string(), |acc, (col, &w)| {
+                        let text = row
+                            .get(col)
+                            .and_then(|cell| cell.value.split('\n').nth(i))
+                            .unwrap_or_default();
+                        let text = if header && idx == 0 {
+                            pad_string_center(text, w, ' ', ' ')
                         } else {
-                            " ".to_string()
+                            text.to_string()
                         };
                         format!(
                             "{}{}{}",
                             acc,
-                            pad_string_right(&text_with_space, w + 2, ' ',),
+                            pad_string_right(&(" ".to_string() + &text), w + 2, ' '),
                             palette[5]
                         )
                     });
-            if idx == 0 {
-                writeln!(&mut buffer, "{}", first_line)?;
-            } else {
-                writeln!(&mut buffer, "{}", separating_line)?;
-            }
-            writeln!(&mut buffer, "{}", text_line)?;
+            lines.push(text_line);
         }
-        writeln!(&mut buffer, "{}", last_line)?;
-        Ok(buffer)
     }
+    lines.push(border(palette[2], palette[7], palette[3]));
+    lines
 }
 
 #[derive(Parser)]
 #[grammar = "mono-diagram/grammar/table.pest"]
 struct TableDiagramParser;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn draw_table_centers_header_and_splits_lines() {
+        let table = Table {
+            width: 1,
+            height: 2,
+            cells: vec![
+                vec![TableCell { value: "ab".into() }],
+                vec![TableCell {
+                    value: "x\nlonger".into(),
+                }],
+            ],
+        };
+        assert_eq!(
+            draw_table(&table, &table.col_width(), Style::Ascii, true),
+            [
+                "+--------+",
+                "|   ab   |",
+                "+--------+",
+                "| x      |",
+                "| longer |",
+                "+--------+"
+            ]
+        );
+    }
+}
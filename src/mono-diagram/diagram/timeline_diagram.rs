@@ -1,10 +1,13 @@
 use std::{cmp::max, io::Write as _};
 
-use anyhow::Error;
 use pest::Parser;
 use pest_derive::Parser;
 
-use crate::attrib::{Attrib, Style};
+use crate::{
+    attrib::{Attrib, Style},
+    error::{ErrorKind, ScriptError},
+    utils::{display_width, unescape},
+};
 
 use super::Diagram;
 
@@ -19,25 +22,24 @@ impl Diagram for TimelineDiagram {
     fn parse_from_str(&mut self, input: &str, attribs: Attrib) -> anyhow::Result<()> {
         let mut timeline_data = Vec::new();
         let diagram = TimelineDiagramParser::parse(Rule::diagram, input)
-            .map_err(|e| {
-                Error::msg(format!(
-                    "parsing error: incorrect timeline grammar, context: {}",
-                    e.line()
-                ))
-            })?
+            .map_err(|e| ScriptError::from_pest(e, "timeline"))?
             .next()
             .unwrap();
         for line in diagram.into_inner() {
             if line.as_rule() == Rule::line {
+                let span = line.as_span();
                 let mut line_inner = line.into_inner();
-                let time = line_inner.next().unwrap().as_str().to_string();
+                let time = unescape(line_inner.next().unwrap().as_str());
                 if time.is_empty() {
-                    return Err(Error::msg(
-                        "parsing error: incorrect timeline grammar: time cannot be empty",
-                    ));
+                    return Err(ScriptError::new(
+                        ErrorKind::Parsing,
+                        "incorrect timeline grammar, time cannot be empty",
+                    )
+                    .with_span(span)
+                    .into());
                 }
-                self.max_width = max(self.max_width, time.len());
-                let description = line_inner.next().unwrap().as_str().to_string();
+                self.max_width = max(self.max_width, display_width(&time));
+                let description = unescape(line_inner.next().unwrap().as_str());
                 timeline_data.push((time, description));
             }
         }
@@ -57,7 +59,7 @@ impl Diagram for TimelineDiagram {
 impl TimelineDiagram {
     fn write_ascii(&self) -> anyhow::Result<Vec<u8>> {
         let mut buffer = Vec::new();
-        let max_left_dash_width = if self.max_width % 2 == 0 {
+        let max_left_dash_width = if self.max_width.is_multiple_of(2) {
             self.max_width / 2
         } else {
             (self.max_width - 1) / 2
@@ -70,8 +72,8 @@ impl TimelineDiagram {
             writeln!(&mut buffer, "{}", empty_line)?;
         }
         for (time, des) in self.data.iter() {
-            let len = time.len();
-            let dashes_left = if len % 2 == 0 { len / 2 } else { (len - 1) / 2 } + 1;
+            let len = display_width(time);
+            let dashes_left = len / 2 + 1;
             let shift_right = max_left_dash_width - dashes_left;
             // First line
             for _ in 0..shift_right {
@@ -91,7 +93,7 @@ impl TimelineDiagram {
                 write!(&mut buffer, " ")?;
             }
             write!(&mut buffer, " ")?;
-            if len % 2 == 0 {
+            if len.is_multiple_of(2) {
                 write!(&mut buffer, " ")?;
             }
             write!(&mut buffer, "{}", time)?;
@@ -130,7 +132,7 @@ impl TimelineDiagram {
 
     fn write_unicode(&self) -> anyhow::Result<Vec<u8>> {
         let mut buffer = Vec::new();
-        let max_left_dash_width = if self.max_width % 2 == 0 {
+        let max_left_dash_width = if self.max_width.is_multiple_of(2) {
             self.max_width / 2
         } else {
             (self.max_width - 1) / 2
@@ -143,8 +145,8 @@ impl TimelineDiagram {
             writeln!(&mut buffer, "{}", empty_line)?;
         }
         for (time, des) in self.data.iter() {
-            let len = time.len();
-            let dashes_left = if len % 2 == 0 { len / 2 } else { (len - 1) / 2 } + 1;
+            let len = display_width(time);
+            let dashes_left = len / 2 + 1;
             let shift_right = max_left_dash_width - dashes_left;
             // First line
             for _ in 0..shift_right {
@@ -161,7 +163,7 @@ impl TimelineDiagram {
                 write!(&mut buffer, " ")?;
             }
             write!(&mut buffer, " ")?;
-            if len % 2 == 0 {
+            if len.is_multiple_of(2) {
                 write!(&mut buffer, " ")?;
             }
             write!(&mut buffer, "{}", time)?;
@@ -0,0 +1,384 @@
+use anyhow::{Error, Result};
+
+use crate::{
+    error::{relocate, ErrorKind, OnError, ScriptError},
+    render_str_with,
+    utils::error_box,
+};
+
+const BEGIN_MARKER: &str = "mono-diagram:begin";
+const OUTPUT_MARKER: &str = "mono-diagram:output";
+const END_MARKER: &str = "mono-diagram:end";
+const FENCE_LANG: &str = "mono-diagram";
+const OUTPUT_FENCE_INFO: &str = "text mono-diagram-output";
+
+/// Re-render every `mono-diagram:begin` ... `mono-diagram:end` region in a document
+///
+/// The text before the begin marker (such as `// ` or `# `) is the comment leader of
+/// the region. The script is read from the following lines, with the leader stripped,
+/// up to a `mono-diagram:output` line. Everything after that, up to the end marker, is
+/// replaced by the rendered diagrams, with each line prefixed by the leader.
+///
+/// If the region starts with an HTML comment (`<!-- mono-diagram:begin`), the script
+/// ends at the closing `-->` line and the diagrams are written in a code block, which
+/// is useful for Markdown documents.
+///
+/// Failed diagrams are handled as `on_error` says, see [`render_block`]. The errors
+/// of failed diagrams are returned along with the document.
+pub fn render_regions(document: &str, on_error: OnError) -> Result<(String, Vec<Error>)> {
+    let lines: Vec<&str> = document.lines().collect();
+    let mut res: Vec<String> = Vec::new();
+    let mut errors: Vec<Error> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let line = lines[i];
+        res.push(line.to_string());
+        i += 1;
+        let Some(marker_pos) = line.find(BEGIN_MARKER) else {
+            continue;
+        };
+        let begin_line = i;
+        let leader = &line[..marker_pos];
+        let html = leader.trim_start().starts_with("<!--");
+        let leader = if html { "" } else { leader };
+        let marker_span = marker_pos..marker_pos + BEGIN_MARKER.len();
+        let region_error = |message: &str| {
+            Error::from(
+                ScriptError::new(ErrorKind::Parsing, message)
+                    .locate(line, 0, marker_span.clone())
+                    .relocate(document, begin_line - 1, 0),
+            )
+        };
+        let unclosed = || region_error("region is not closed by 'mono-diagram:end'");
+        if html && line[marker_pos..].contains("-->") {
+            return Err(region_error(
+                "the script should be written inside the begin comment, before '-->'",
+            ));
+        }
+
+        // Collect script
+        let mut script = String::new();
+        loop {
+            let line = lines.get(i).ok_or_else(unclosed)?;
+            res.push(line.to_string());
+            i += 1;
+            if line.contains(OUTPUT_MARKER) || html && line.trim() == "-->" {
+                break;
+            }
+            if line.contains(END_MARKER) {
+                return Err(unclosed());
+            }
+            script.push_str(strip_leader(line, leader));
+            script.push('\n');
+        }
+
+        // Skip the old output
+        let output_start = i;
+        while !lines.get(i).ok_or_else(unclosed)?.contains(END_MARKER) {
+            i += 1;
+        }
+
+        let Some(output) = render_block(&script, on_error, &mut errors, |e| {
+            relocate(e, document, begin_line, leader.chars().count())
+        })?
+        else {
+            // Keep the old output
+            res.extend(lines[output_start..i].iter().map(|l| l.to_string()));
+            continue;
+        };
+        let output = output.trim_end_matches('\n');
+        if html {
+            let fence = code_fence(output);
+            res.push(format!("{}text", fence));
+            res.extend(output.lines().map(|l| l.to_string()));
+            res.push(fence);
+        } else {
+            res.extend(output.lines().map(|l| {
+                if l.is_empty() {
+                    leader.trim_end().to_string()
+                } else {
+                    format!("{}{}", leader, l)
+                }
+            }));
+        }
+    }
+
+    let newline = if document.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut res = res.join(newline);
+    if document.ends_with('\n') {
+        res.push_str(newline);
+    }
+    Ok((res, errors))
+}
+
+/// Render every ```` ```mono-diagram ```` code block in a Markdown document
+///
+/// Each code block is replaced by a code block of the rendered diagrams. If `keep_source`
+/// is set, the source code block is kept and the rendered diagrams are inserted right
+/// after it, replacing the diagrams inserted by a previous run.
+///
+/// Failed diagrams are handled as `on_error` says, see [`render_block`]. The errors
+/// of failed diagrams are returned along with the document.
+pub fn render_markdown(
+    document: &str,
+    keep_source: bool,
+    on_error: OnError,
+) -> Result<(String, Vec<Error>)> {
+    let lines: Vec<&str> = document.lines().collect();
+    let mut res: Vec<String> = Vec::new();
+    let mut errors: Vec<Error> = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        let Some((fence, info)) = open_fence(lines[i]) else {
+            res.push(lines[i].to_string());
+            i += 1;
+            continue;
+        };
+        let start = i;
+        let end = (start + 1..lines.len())
+            .find(|&j| is_close_fence(lines[j], fence))
+            .unwrap_or(lines.len());
+        i = (end + 1).min(lines.len());
+        if info.split_whitespace().next() != Some(FENCE_LANG) {
+            res.extend(lines[start..i].iter().map(|l| l.to_string()));
+            continue;
+        }
+
+        let script = lines[start + 1..end].join("\n");
+        let Some(output) = render_block(&script, on_error, &mut errors, |e| {
+            relocate(e, document, start + 1, 0)
+        })?
+        else {
+            // Keep the code block as it is
+            res.extend(lines[start..i].iter().map(|l| l.to_string()));
+            continue;
+        };
+        let output = output.trim_end_matches('\n');
+        if keep_source {
+            res.extend(lines[start..i].iter().map(|l| l.to_string()));
+            // Skip the diagrams inserted by a previous run
+            let mut next = i;
+            while next < lines.len() && lines[next].trim().is_empty() {
+                next += 1;
+            }
+            if let Some((fence, info)) = lines.get(next).and_then(|l| open_fence(l)) {
+                if info == OUTPUT_FENCE_INFO {
+                    i = (next + 1..lines.len())
+                        .find(|&j| is_close_fence(lines[j], fence))
+                        .map_or(lines.len(), |j| j + 1);
+                }
+            }
+            res.push(String::new());
+        }
+        let fence = code_fence(output);
+        res.push(format!("{}{}", fence, OUTPUT_FENCE_INFO));
+        res.extend(output.lines().map(|l| l.to_string()));
+        res.push(fence);
+    }
+
+    let newline = if document.contains("\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    let mut res = res.join(newline);
+    if document.ends_with('\n') {
+        res.push_str(newline);
+    }
+    Ok((res, errors))
+}
+
+/// Render the script of a block in a document, moving errors to the document with
+/// `relocate`
+///
+/// With [`OnError::Abort`], the first error is returned. Otherwise the errors are
+/// collected into `errors`, and a block whose diagrams all fail is rendered as an error
+/// box for [`OnError::Inline`], or as `None` for [`OnError::Skip`] to leave the block
+/// untouched.
+fn render_block(
+    script: &str,
+    on_error: OnError,
+    errors: &mut Vec<Error>,
+    relocate: impl Fn(Error) -> Error,
+) -> Result<Option<String>> {
+    match render_str_with(script, on_error) {
+        Ok((output, block_errors)) => {
+            let failed = !block_errors.is_empty();
+            errors.extend(block_errors.into_iter().map(&relocate));
+            if failed && output.is_empty() {
+                return Ok(None);
+            }
+            Ok(Some(output))
+        }
+        Err(e) if on_error == OnError::Abort => Err(relocate(e)),
+        Err(e) => {
+            let e = relocate(e);
+            let output = (on_error == OnError::Inline).then(|| error_box(&e));
+            errors.push(e);
+            Ok(output)
+        }
+    }
+}
+
+/// Parse an opening code fence, returning the fence and the info string
+fn open_fence(line: &str) -> Option<(&str, &str)> {
+    let trimmed = line.trim_start();
+    if line.len() - trimmed.len() > 3 {
+        return None;
+    }
+    let c = trimmed.chars().next().filter(|c| *c == '`' || *c == '~')?;
+    let len = trimmed.len() - trimmed.trim_start_matches(c).len();
+    if len < 3 {
+        return None;
+    }
+    let (fence, info) = trimmed.split_at(len);
+    if c == '`' && info.contains('`') {
+        return None;
+    }
+    Some((fence, info.trim()))
+}
+
+fn is_close_fence(line: &str, fence: &str) -> bool {
+    let trimmed = line.trim();
+    let c = fence.chars().next().unwrap();
+    trimmed.len() >= fence.len() && trimmed.chars().all(|x| x == c)
+}
+
+/// A Markdown code fence long enough to hold `content`
+fn code_fence(content: &str) -> String {
+    let mut longest = 0;
+    let mut cur = 0;
+    for c in content.chars() {
+        if c == '`' {
+            cur += 1;
+            longest = longest.max(cur);
+        } else {
+            cur = 0;
+        }
+    }
+    "`".repeat(longest.max(2) + 1)
+}
+
+fn strip_leader<'a>(line: &'a str, leader: &str) -> &'a str {
+    line.strip_prefix(leader)
+        .or_else(|| line.trim_end().strip_prefix(leader.trim_end()))
+        .unwrap_or(line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(e: &Error) -> (usize, usize) {
+        let l = e
+            .downcast_ref::<ScriptError>()
+            .unwrap()
+            .location
+            .as_ref()
+            .unwrap();
+        (l.line, l.col)
+    }
+
+    #[test]
+    fn regions_are_rendered_with_their_leader() {
+        let document = "fn main() {}\n\
+            // mono-diagram:begin\n\
+            // [table]\n\
+            // a|b\n\
+            // mono-diagram:output\n\
+            // stale output\n\
+            // mono-diagram:end\n";
+        let (output, errors) = render_regions(document, OnError::Abort).unwrap();
+        assert!(errors.is_empty());
+        assert_eq!(
+            output,
+            "fn main() {}\n\
+            // mono-diagram:begin\n\
+            // [table]\n\
+            // a|b\n\
+            // mono-diagram:output\n\
+            // +---+---+\n\
+            // | a | b |\n\
+            // +---+---+\n\
+            // mono-diagram:end\n"
+        );
+        // Rendering again leaves the document as it is
+        assert_eq!(render_regions(&output, OnError::Abort).unwrap().0, output);
+    }
+
+    #[test]
+    fn html_regions_are_rendered_in_code_blocks() {
+        let document = "<!-- mono-diagram:begin\n[table]\na|b\n-->\nold\n<!-- mono-diagram:end -->";
+        let (output, _) = render_regions(document, OnError::Abort).unwrap();
+        assert_eq!(
+            output,
+            "<!-- mono-diagram:begin\n[table]\na|b\n-->\n\
+            ```text\n+---+---+\n| a | b |\n+---+---+\n```\n\
+            <!-- mono-diagram:end -->"
+        );
+        assert_eq!(render_regions(&output, OnError::Abort).unwrap().0, output);
+    }
+
+    #[test]
+    fn region_errors_are_located_in_the_document() {
+        let document = "text\n# mono-diagram:begin\n# [table]\n# a|b\n# mono-diagram:output\n";
+        let e = render_regions(document, OnError::Abort).unwrap_err();
+        assert_eq!(location(&e), (2, 3));
+        assert!(e.to_string().contains("not closed"), "{}", e);
+
+        let document = "x\n  # mono-diagram:begin\n  # [dag]\n  # a->b->a\n  # mono-diagram:output\n  # mono-diagram:end\n";
+        let (output, errors) = render_regions(document, OnError::Skip).unwrap();
+        assert_eq!(output, document);
+        assert_eq!(location(&errors[0]), (3, 5));
+    }
+
+    #[test]
+    fn markdown_blocks_are_replaced_by_their_diagrams() {
+        let document = "# Title\n\n```mono-diagram\n[table]\na|b\n```\n\n```rust\nfn f() {}\n```\n";
+        let (output, _) = render_markdown(document, false, OnError::Abort).unwrap();
+        assert_eq!(
+            output,
+            "# Title\n\n```text mono-diagram-output\n+---+---+\n| a | b |\n+---+---+\n```\n\n\
+            ```rust\nfn f() {}\n```\n"
+        );
+    }
+
+    #[test]
+    fn markdown_sources_can_be_kept() {
+        let document = "~~~~ mono-diagram\n[table]\n`a`|b\n~~~~\ntext\n";
+        let (output, _) = render_markdown(document, true, OnError::Abort).unwrap();
+        assert_eq!(
+            output,
+            "~~~~ mono-diagram\n[table]\n`a`|b\n~~~~\n\n\
+            ```text mono-diagram-output\n+-----+---+\n| `a` | b |\n+-----+---+\n```\ntext\n"
+        );
+        // The diagrams of a previous run are replaced rather than added to
+        let (again, _) = render_markdown(&output, true, OnError::Abort).unwrap();
+        assert_eq!(again, output);
+    }
+
+    #[test]
+    fn code_fences_are_longer_than_the_backticks_inside() {
+        assert_eq!(code_fence("a ``` b"), "````");
+        assert_eq!(code_fence("a ` b"), "```");
+        assert_eq!(
+            open_fence("   ```mono-diagram x"),
+            Some(("```", "mono-diagram x"))
+        );
+        assert_eq!(open_fence("    ```mono-diagram"), None);
+        assert_eq!(open_fence("``` a`b"), None);
+        assert!(is_close_fence("`````", "````"));
+        assert!(!is_close_fence("```", "````"));
+    }
+
+    #[test]
+    fn markdown_errors_are_located_in_the_document() {
+        let document = "text\n```mono-diagram\n[dag]\na->b->a\n```\n";
+        let e = render_markdown(document, false, OnError::Abort).unwrap_err();
+        assert_eq!(location(&e), (3, 1));
+    }
+}
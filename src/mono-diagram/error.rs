@@ -0,0 +1,282 @@
+use std::{
+    fmt::{self, Display},
+    ops::Range,
+    str::FromStr,
+};
+
+use pest::{error::InputLocation, RuleType, Span};
+
+use crate::utils::display_width;
+
+/// Category of a script error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorKind {
+    /// The script does not follow the grammar
+    Parsing,
+    /// The script follows the grammar, but describes an invalid diagram
+    Diagram,
+}
+
+/// What to do with the other diagrams when one diagram fails
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OnError {
+    /// Stop at the first failed diagram
+    #[default]
+    Abort,
+    /// Render the other diagrams, drawing a box with the error in place of a failed one
+    Inline,
+    /// Render the other diagrams, leaving failed ones out
+    Skip,
+}
+
+impl FromStr for OnError {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "abort" => Ok(Self::Abort),
+            "inline" => Ok(Self::Inline),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!(
+                "unknown mode '{}', expected one of abort, inline, skip",
+                s
+            )),
+        }
+    }
+}
+
+/// Error in a diagram script, with the position it occurs at
+///
+/// Diagram parsers create errors with spans relative to their own input. The spans
+/// are turned into line and column numbers of the whole script by [`crate::parse_str`].
+#[derive(Debug, Clone)]
+pub struct ScriptError {
+    pub kind: ErrorKind,
+    pub message: String,
+    /// Tag of the diagram the error belongs to
+    pub tag: Option<String>,
+    /// Path of the script file
+    pub path: Option<String>,
+    pub location: Option<Location>,
+    span: Option<Range<usize>>,
+}
+
+/// Position of an error in a script
+#[derive(Debug, Clone)]
+pub struct Location {
+    /// Line number, starting from 1
+    pub line: usize,
+    /// Column number in characters, starting from 1
+    pub col: usize,
+    /// Number of characters the error spans on the line
+    pub len: usize,
+    /// Text of the line
+    pub source_line: String,
+}
+
+impl ScriptError {
+    pub fn new(kind: ErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            tag: None,
+            path: None,
+            location: None,
+            span: None,
+        }
+    }
+
+    /// Create an error from a grammar error of the `name` diagram
+    pub fn from_pest<R: RuleType>(e: pest::error::Error<R>, name: &str) -> Self {
+        let span = match e.location {
+            InputLocation::Pos(p) => p..p,
+            InputLocation::Span((s, e)) => s..e,
+        };
+        Self {
+            span: Some(span),
+            ..Self::new(
+                ErrorKind::Parsing,
+                format!("incorrect {} grammar, {}", name, e.variant.message()),
+            )
+        }
+    }
+
+    /// Point the error at a span of the diagram input
+    pub fn with_span(mut self, span: Span<'_>) -> Self {
+        self.span = Some(span.start()..span.end());
+        self
+    }
+
+    pub fn with_tag(mut self, tag: &str) -> Self {
+        self.tag = Some(tag.to_string());
+        self
+    }
+
+    pub fn with_path(mut self, path: &str) -> Self {
+        self.path = Some(path.to_string());
+        self
+    }
+
+    /// Locate the error in `script`, given the offset of the diagram input in it
+    ///
+    /// Errors without a span are located at `fallback`, which is relative to `script`.
+    pub(crate) fn locate(mut self, script: &str, offset: usize, fallback: Range<usize>) -> Self {
+        let span = self
+            .span
+            .take()
+            .map(|s| (s.start + offset)..(s.end + offset))
+            .unwrap_or(fallback);
+        let start = span.start.min(script.len());
+        let line_start = script[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = script[start..]
+            .find('\n')
+            .map_or(script.len(), |i| start + i);
+        let source_line = script[line_start..line_end].trim_end_matches('\r');
+        let col = script[line_start..start].chars().count() + 1;
+        let len = script[start..span.end.clamp(start, line_end)]
+            .chars()
+            .count();
+        self.location = Some(Location {
+            line: script[..line_start].matches('\n').count() + 1,
+            col,
+            len: len.max(1),
+            source_line: source_line.to_string(),
+        });
+        self
+    }
+
+    /// Move a located error into a `document` that embeds the script, starting after
+    /// `line_offset` lines, with `col_offset` characters before each script line
+    pub(crate) fn relocate(
+        mut self,
+        document: &str,
+        line_offset: usize,
+        col_offset: usize,
+    ) -> Self {
+        if let Some(l) = self.location.as_mut() {
+            l.line += line_offset;
+            l.col += col_offset;
+            if let Some(source_line) = document.lines().nth(l.line - 1) {
+                l.source_line = source_line.to_string();
+            }
+        }
+        self
+    }
+}
+
+impl Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let kind = match self.kind {
+            ErrorKind::Parsing => "parsing error",
+            ErrorKind::Diagram => "diagram error",
+        };
+        write!(f, "{}: {}", kind, self.message)?;
+        let mut position: Vec<String> = self.path.iter().cloned().collect();
+        if let Some(l) = &self.location {
+            position.push(format!("{}:{}", l.line, l.col));
+        }
+        let gutter = self
+            .location
+            .as_ref()
+            .map_or(0, |l| l.line.to_string().len());
+        if !position.is_empty() {
+            write!(f, "\n{:gutter$}--> {}", "", position.join(":"))?;
+        }
+        if let Some(l) = &self.location {
+            // Align the carets with the columns the text takes on a terminal
+            let before: String = l.source_line.chars().take(l.col - 1).collect();
+            let spanned: String = l.source_line.chars().skip(l.col - 1).take(l.len).collect();
+            write!(f, "\n{:gutter$} |", "")?;
+            write!(f, "\n{} | {}", l.line, l.source_line)?;
+            write!(
+                f,
+                "\n{:gutter$} | {}{}",
+                "",
+                " ".repeat(display_width(&before)),
+                "^".repeat(display_width(&spanned).max(1))
+            )?;
+        }
+        if let Some(tag) = &self.tag {
+            write!(f, "\n{:gutter$} = in [{}] diagram", "", tag)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// Attach the path of the script file to an error, if it is a [`ScriptError`]
+pub fn with_path(e: anyhow::Error, path: &str) -> anyhow::Error {
+    match e.downcast::<ScriptError>() {
+        Ok(e) => e.with_path(path).into(),
+        Err(e) => e,
+    }
+}
+
+/// Move an error of a script embedded in `document` to its position in the document,
+/// see [`ScriptError::relocate`]
+pub(crate) fn relocate(
+    e: anyhow::Error,
+    document: &str,
+    line_offset: usize,
+    col_offset: usize,
+) -> anyhow::Error {
+    match e.downcast::<ScriptError>() {
+        Ok(e) => e.relocate(document, line_offset, col_offset).into(),
+        Err(e) => e,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn spanned(start: usize, end: usize) -> ScriptError {
+        ScriptError {
+            span: Some(start..end),
+            ..ScriptError::new(ErrorKind::Diagram, "bad")
+        }
+    }
+
+    #[test]
+    fn errors_are_located_by_line_and_character() {
+        let script = "[dag]\r\n节点->b\r\nc->d\r\n";
+        // The diagram input starts after the title, and the span is in it
+        let offset = script.find('节').unwrap();
+        let e = spanned(11, 12).locate(script, offset, 0..0);
+        let l = e.location.as_ref().unwrap();
+        assert_eq!((l.line, l.col, l.len), (3, 1, 1));
+        assert_eq!(l.source_line, "c->d");
+        let e = spanned(6, 8).locate(script, offset, 0..0);
+        let l = e.location.as_ref().unwrap();
+        assert_eq!((l.line, l.col, l.len), (2, 3, 2));
+        // Errors without a span are located at the fallback
+        let e = ScriptError::new(ErrorKind::Diagram, "bad").locate(script, offset, 1..4);
+        let l = e.location.as_ref().unwrap();
+        assert_eq!((l.line, l.col, l.len), (1, 2, 3));
+    }
+
+    #[test]
+    fn relocated_errors_point_into_the_document() {
+        let document = "intro\n// [table]\n// a|b\n";
+        let e = spanned(2, 3)
+            .locate("[table]\na|b\n", 8, 0..0)
+            .relocate(document, 1, 3);
+        let l = e.location.as_ref().unwrap();
+        assert_eq!((l.line, l.col), (3, 6));
+        assert_eq!(l.source_line, "// a|b");
+        assert_eq!(
+            e.to_string(),
+            "diagram error: bad\n --> 3:6\n  |\n3 | // a|b\n  |      ^"
+        );
+    }
+
+    #[test]
+    fn carets_follow_the_display_width() {
+        let e = spanned(6, 8).with_tag("dag").locate("名字->b\n", 0, 0..0);
+        assert_eq!(
+            e.to_string(),
+            "diagram error: bad\n --> 1:3\n  |\n1 | 名字->b\n  |     ^^\n  = in [dag] diagram"
+        );
+    }
+}
@@ -0,0 +1,49 @@
+//! Generate plain-text diagrams from a certain syntax.
+//!
+//! A script may contain multiple diagrams, each starting with a tag such as
+//! `[table]`. Use [`render_str`] to render a whole script at once, or
+//! [`parse_str`] and [`write`] to work with the parsed [`Diagram`]s.
+//!
+//! ```
+//! let output = mono_diagram::render_str("[table]\na|b\nc|d\n").unwrap();
+//! assert!(output.starts_with("+---+---+"));
+//! ```
+
+pub mod attrib;
+mod data_structure;
+pub mod diagram;
+pub mod document;
+pub mod error;
+mod parser;
+mod utils;
+
+use anyhow::{Error, Result};
+
+pub use attrib::{Attrib, Style};
+pub use diagram::{
+    binary_tree_diagram::BinaryTreeDiagram, class_diagram::ClassDiagram, dag_diagram::DagGraph,
+    gantt_diagram::GanttDiagram, grid_diagram::GridDiagram, sequence_diagram::SequenceDiagram,
+    table_diagram::TableDiagram, timeline_diagram::TimelineDiagram, Diagram,
+};
+pub use document::{render_markdown, render_regions};
+pub use error::{ErrorKind, OnError, ScriptError};
+pub use parser::{parse, parse_each, parse_str, read_script, write, write_each};
+pub use utils::{add_prefix, display_width};
+
+/// Render all diagrams in a script to a string
+pub fn render_str(input: &str) -> Result<String> {
+    let diagrams = parse_str(input)?;
+    Ok(String::from_utf8_lossy(&write(&diagrams)?).to_string())
+}
+
+/// Render all diagrams in a script to a string, handling failed diagrams as `on_error` says
+///
+/// The errors of failed diagrams are returned along with the output. With
+/// [`OnError::Abort`], the first error is returned instead, like [`render_str`].
+pub fn render_str_with(input: &str, on_error: OnError) -> Result<(String, Vec<Error>)> {
+    if on_error == OnError::Abort {
+        return render_str(input).map(|output| (output, Vec::new()));
+    }
+    let (output, errors) = write_each(parse_each(input)?, on_error);
+    Ok((String::from_utf8_lossy(&output).to_string(), errors))
+}
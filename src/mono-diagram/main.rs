@@ -1,42 +1,115 @@
 mod args;
-mod attrib;
-mod data_structure;
-mod diagram;
-mod parser;
-mod utils;
+mod watch;
 
+use std::{fs, process};
+
+use anyhow::{Error, Result};
 use args::Cli;
 use clap::Parser;
 use clipboard::{ClipboardContext, ClipboardProvider};
-use parser::{parse, write};
-use utils::add_prefix;
+use mono_diagram::{
+    add_prefix, error::with_path, read_script, render_markdown, render_regions, render_str_with,
+    ErrorKind, ScriptError,
+};
+use watch::watch;
+
+/// Exit code for a script that does not follow the grammar
+const EXIT_PARSING_ERROR: i32 = 1;
+/// Exit code for a script that describes an invalid diagram
+const EXIT_DIAGRAM_ERROR: i32 = 3;
+/// Exit code for failures reading or writing files, the terminal or the clipboard
+const EXIT_IO_ERROR: i32 = 4;
 
 fn main() {
-    let cli = Cli::parse();
-    let prefix = cli.prefix;
-    let copy = cli.copy;
-    let file = &cli.file_path;
-    let result = parse(file).and_then(|d| write(&d));
-    match result {
-        Ok(d) => {
-            // Print to console
-            println!(
-                "{}",
-                add_prefix(
-                    String::from_utf8_lossy(&d).to_string(),
-                    &prefix.clone().unwrap_or("".to_string())
-                )
-            );
-            if copy {
-                // Copy to clipboard
-                let mut ctx: ClipboardContext = ClipboardProvider::new().unwrap();
-                ctx.set_contents(add_prefix(
-                    String::from_utf8_lossy(&d).to_string(),
-                    &prefix.unwrap_or("".to_string()),
-                ))
-                .unwrap();
-            }
+    let cli = Cli::parse().validate().unwrap_or_else(|e| e.exit());
+    let errors = run(cli).unwrap_or_else(|e| vec![e]);
+    let Some(first) = errors.first() else {
+        return;
+    };
+    for (i, e) in errors.iter().enumerate() {
+        if i > 0 {
+            eprintln!();
         }
-        Err(e) => println!("{}", e),
+        eprintln!("{}", e);
+    }
+    if errors.len() > 1 {
+        eprintln!("\n{} diagrams failed", errors.len());
+    }
+    process::exit(exit_code(first));
+}
+
+/// Exit code for an error, by the kind of script error it is, if any
+fn exit_code(e: &Error) -> i32 {
+    match e.downcast_ref::<ScriptError>().map(|e| e.kind) {
+        Some(ErrorKind::Parsing) => EXIT_PARSING_ERROR,
+        Some(ErrorKind::Diagram) => EXIT_DIAGRAM_ERROR,
+        None => EXIT_IO_ERROR,
+    }
+}
+
+/// Run the command, returning the errors of the diagrams that failed without
+/// stopping it
+fn run(cli: Cli) -> Result<Vec<Error>> {
+    let file = cli.file_path.as_deref();
+    if cli.watch {
+        return watch(file.unwrap_or("-"), cli.prefix).map(|_| Vec::new());
+    }
+    let script = read_script(file)?;
+    let path = file.filter(|f| *f != "-").unwrap_or("<stdin>");
+    if cli.in_place {
+        let (document, errors) =
+            render_regions(&script, cli.on_error).map_err(|e| with_path(e, path))?;
+        write_file(path, &document)?;
+        return Ok(errors.into_iter().map(|e| with_path(e, path)).collect());
+    }
+    let (output, errors) = if cli.markdown {
+        render_markdown(&script, cli.keep_source, cli.on_error).map_err(|e| with_path(e, path))?
+    } else {
+        let (output, errors) =
+            render_str_with(&script, cli.on_error).map_err(|e| with_path(e, path))?;
+        (
+            add_prefix(output, &cli.prefix.unwrap_or("".to_string())) + "\n",
+            errors,
+        )
+    };
+    let errors = errors.into_iter().map(|e| with_path(e, path)).collect();
+    if let Some(output_path) = cli.output {
+        // Write to file
+        write_file(&output_path, &output)?;
+    } else {
+        // Print to console
+        print!("{}", output);
+    }
+    if cli.copy {
+        // Copy to clipboard
+        let mut ctx: ClipboardContext = ClipboardProvider::new()
+            .map_err(|e| Error::msg(format!("io error: fail to access clipboard: {}", e)))?;
+        ctx.set_contents(output)
+            .map_err(|e| Error::msg(format!("io error: fail to copy to clipboard: {}", e)))?;
+    }
+    Ok(errors)
+}
+
+fn write_file(path: &str, content: &str) -> Result<()> {
+    fs::write(path, content)
+        .map_err(|e| Error::msg(format!("io error: fail to write {}: {}", path, e)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_exit_with_the_code_of_their_kind() {
+        let parsing = render_str_with("[table]\na|b\n[dag]\na {x: 1}\n", Default::default());
+        assert_eq!(exit_code(&parsing.unwrap_err()), EXIT_PARSING_ERROR);
+        let diagram = render_str_with("[dag]\na {class: missing}\n", Default::default());
+        assert_eq!(exit_code(&diagram.unwrap_err()), EXIT_DIAGRAM_ERROR);
+        let io = read_script(Some("no/such/script.txt"));
+        assert_eq!(exit_code(&io.unwrap_err()), EXIT_IO_ERROR);
+        // Errors keep their kind once the path of the script is added to them
+        let diagram = render_str_with("[dag]\na {class: missing}\n", Default::default());
+        let e = with_path(diagram.unwrap_err(), "script.txt");
+        assert_eq!(exit_code(&e), EXIT_DIAGRAM_ERROR);
     }
 }
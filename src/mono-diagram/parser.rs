@@ -1,6 +1,6 @@
 use std::{
     fs,
-    io::{Read, Seek},
+    io::{self, Read},
 };
 
 use anyhow::{Error, Result};
@@ -8,49 +8,89 @@ use pest::Parser;
 use pest_derive::Parser;
 
 use crate::{
-    attrib::Attrib,
     diagram::{
-        binary_tree_diagram::BinaryTreeDiagram, dag_diagram::DagGraph, gantt_diagram::GanttDiagram,
-        grid_diagram::GridDiagram, table_diagram::TableDiagram, timeline_diagram::TimelineDiagram,
-        Diagram,
+        binary_tree_diagram::BinaryTreeDiagram, class_diagram::ClassDiagram, dag_diagram::DagGraph,
+        gantt_diagram::GanttDiagram, grid_diagram::GridDiagram, sequence_diagram::SequenceDiagram,
+        table_diagram::TableDiagram, timeline_diagram::TimelineDiagram, Diagram,
     },
+    error::{with_path, OnError, ScriptError},
+    utils::error_box,
 };
 
+/// Read input script from a file, or from stdin if the path is `-` or not given
+pub fn read_script(script_path: Option<&str>) -> Result<String> {
+    match script_path {
+        Some(path) if path != "-" => fs::read_to_string(path)
+            .map_err(|e| Error::msg(format!("io error: fail to read {}: {}", path, e))),
+        _ => {
+            let mut script = String::new();
+            io::stdin()
+                .read_to_string(&mut script)
+                .map_err(|e| Error::msg(format!("io error: fail to read stdin: {}", e)))?;
+            Ok(script)
+        }
+    }
+}
+
 /// Parse all diagrams in input file
 pub fn parse(script_path: &str) -> Result<Vec<Box<dyn Diagram>>> {
-    let mut parsed_diagrams: Vec<Box<dyn Diagram>> = Vec::new();
-    let mut file_content = String::new();
-    let mut file = fs::File::open(script_path)?;
-    file.seek(std::io::SeekFrom::Start(0))?;
-    file.read_to_string(&mut file_content)?;
-    let main = ScriptParser::parse(Rule::main, &file_content)
-        .map_err(|e| Error::msg(format!("parsing error: {}", e.line())))?
+    parse_str(&read_script(Some(script_path))?).map_err(|e| with_path(e, script_path))
+}
+
+/// Parse all diagrams in input string
+pub fn parse_str(script: &str) -> Result<Vec<Box<dyn Diagram>>> {
+    parse_each(script)?.into_iter().collect()
+}
+
+/// Parse each diagram in input string on its own, so a failed diagram does not stop
+/// the others
+///
+/// Only an error in the overall script structure is returned as the outer error.
+pub fn parse_each(script: &str) -> Result<Vec<Result<Box<dyn Diagram>>>> {
+    let mut parsed_diagrams: Vec<Result<Box<dyn Diagram>>> = Vec::new();
+    let main = ScriptParser::parse(Rule::main, script)
+        .map_err(|e| ScriptError::from_pest(e, "script").locate(script, 0, 0..0))?
         .next()
         .unwrap();
     for diagram in main.into_inner() {
         if diagram.as_rule() == Rule::diagram {
             let mut diagram_inner = diagram.into_inner();
-            let mut d = init_diagram(
-                diagram_inner
-                    .next()
-                    .unwrap()
-                    .as_str()
-                    .trim()
-                    .trim_end_matches(']')
-                    .trim_start_matches('[')
-                    .trim(),
-            );
-            let mut attribs = Attrib::default();
-            let mut content = "";
+            let title = diagram_inner.next().unwrap();
+            let tag = title
+                .as_str()
+                .trim()
+                .trim_end_matches(']')
+                .trim_start_matches('[')
+                .trim();
+            let mut d = init_diagram(tag);
+            // Errors are located from the start of the part of the diagram they are in
+            let mut attribs = ("{}", 0);
+            let mut content = ("", 0);
             for next in diagram_inner {
                 if next.as_rule() == Rule::attribs {
-                    attribs = Attrib::parse_from_str(next.as_str())?;
+                    attribs = (next.as_str(), next.as_span().start());
                 } else if next.as_rule() == Rule::content {
-                    content = next.as_str();
+                    content = (next.as_str(), next.as_span().start());
                 }
             }
-            d.parse_from_str(content, attribs)?;
-            parsed_diagrams.push(d);
+            let mut a = d.default_attribs();
+            let parsed = a
+                .update_from_str(attribs.0)
+                .map(|_| a)
+                .map_err(|e| (e, attribs.1))
+                .and_then(|a| d.parse_from_str(content.0, a).map_err(|e| (e, content.1)))
+                .map(|_| d)
+                .map_err(|(e, offset)| {
+                    let title_span = title.as_span().start()..title.as_span().end();
+                    match e.downcast::<ScriptError>() {
+                        Ok(e) => e
+                            .locate(script, offset, title_span)
+                            .with_tag(&tag.to_ascii_lowercase())
+                            .into(),
+                        Err(e) => e,
+                    }
+                });
+            parsed_diagrams.push(parsed);
         }
     }
     Ok(parsed_diagrams)
@@ -60,12 +100,41 @@ pub fn parse(script_path: &str) -> Result<Vec<Box<dyn Diagram>>> {
 pub fn write(diagrams: &Vec<Box<dyn Diagram>>) -> Result<Vec<u8>> {
     let mut buffer = Vec::new();
     for d in diagrams {
-        buffer.append(&mut d.write().unwrap());
+        buffer.append(&mut d.write()?);
         buffer.push(b'\n');
     }
     Ok(buffer)
 }
 
+/// Write every diagram that can be written to buffer, returning the errors of the
+/// failed ones along with it
+///
+/// A failed diagram is replaced by a box with its error for [`OnError::Inline`], and
+/// left out otherwise.
+pub fn write_each(
+    diagrams: Vec<Result<Box<dyn Diagram>>>,
+    on_error: OnError,
+) -> (Vec<u8>, Vec<Error>) {
+    let mut buffer = Vec::new();
+    let mut errors = Vec::new();
+    for d in diagrams {
+        match d.and_then(|d| d.write()) {
+            Ok(mut output) => {
+                buffer.append(&mut output);
+                buffer.push(b'\n');
+            }
+            Err(e) => {
+                if on_error == OnError::Inline {
+                    buffer.extend_from_slice(error_box(&e).as_bytes());
+                    buffer.push(b'\n');
+                }
+                errors.push(e);
+            }
+        }
+    }
+    (buffer, errors)
+}
+
 /// Initialization
 fn init_diagram(title: &str) -> Box<dyn Diagram> {
     match title.to_ascii_lowercase().as_str() {
@@ -73,6 +142,10 @@ fn init_diagram(title: &str) -> Box<dyn Diagram> {
         "table" => Box::<TableDiagram>::default(),
         "grid" => Box::<GridDiagram>::default(),
         "dag" => Box::<DagGraph>::default(),
+        "digraph" => Box::new(DagGraph::digraph()),
+        "graph" => Box::new(DagGraph::graph()),
+        "class" => Box::<ClassDiagram>::default(),
+        "sequence" => Box::<SequenceDiagram>::default(),
         "timeline" => Box::<TimelineDiagram>::default(),
         "gantt" => Box::<GanttDiagram>::default(),
         _ => unreachable!(),
@@ -81,4 +154,134 @@ fn init_diagram(title: &str) -> Box<dyn Diagram> {
 
 #[derive(Parser)]
 #[grammar = "mono-diagram/grammar/script.pest"]
-pub struct ScriptParser;
+struct ScriptParser;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Render a script, checking that its comments are left out and its text is kept
+    fn assert_renders(script: &str, texts: &[&str]) {
+        let output = String::from_utf8(write(&parse_str(script).unwrap()).unwrap()).unwrap();
+        assert!(!output.contains("hidden"), "{}", output);
+        for text in texts {
+            assert!(output.contains(text), "{} is not in\n{}", text, output);
+        }
+    }
+
+    #[test]
+    fn binary_tree_comments_and_escapes() {
+        assert_renders(
+            "[binary_tree]\n# hidden\na->b,c   // hidden\na:\\#1 café\nb:x\\/y\nc:数据\n",
+            &["#1 café", "x/y", "数据"],
+        );
+    }
+
+    #[test]
+    fn table_comments_and_escapes() {
+        assert_renders(
+            "[table]\n# hidden\n\\#1|café // hidden\nx\\/y|数据\n",
+            &["| #1  | café |", "| x/y | 数据 |"],
+        );
+    }
+
+    #[test]
+    fn grid_comments_and_escapes() {
+        assert_renders(
+            "[grid]\n// hidden\nwidth: 3\nheight: 2\n# hidden\n1,1:数\n2,2:\\#\n",
+            &["|数 |", "| # |"],
+        );
+    }
+
+    #[test]
+    fn graph_comments_and_escapes() {
+        for (tag, edge) in [("dag", "->"), ("digraph", "->"), ("graph", "--")] {
+            assert_renders(
+                &format!(
+                    "[{}]\n# hidden\na{}b // hidden\na: \\#1 café\nb: x\\/y 数据\n",
+                    tag, edge
+                ),
+                &["│ #1 café │", "│ x/y 数据 │"],
+            );
+        }
+    }
+
+    #[test]
+    fn class_comments_and_escapes() {
+        assert_renders(
+            "[class]\n# hidden\nclass Cafe { x\\/y: 数据 // hidden\n}\nCafe --> B : \\#1\n",
+            &["│ x/y: 数据 │", " #1"],
+        );
+    }
+
+    #[test]
+    fn sequence_comments_and_escapes() {
+        assert_renders(
+            "[sequence]\n# hidden\nparticipant a: café\na -> b: x\\/y 数据 // hidden\nnote over a: \\#1\n",
+            &["| café |", "x/y 数据", "| #1 |"],
+        );
+    }
+
+    #[test]
+    fn timeline_comments_and_escapes() {
+        assert_renders(
+            "[timeline]\n# hidden\n2022|\\#1 café // hidden\n2023|x\\/y 数据\n",
+            &[">--- #1 café", ">--- x/y 数据"],
+        );
+    }
+
+    #[test]
+    fn gantt_comments_and_escapes() {
+        assert_renders(
+            "[gantt]\n# hidden\ntimeline: \\#1|café\nx\\/y 数据| 0 ~ 1 // hidden\n",
+            &["#1", "café", " x/y 数据 |"],
+        );
+    }
+
+    /// Script of two tables with a broken graph between them
+    const ONE_BROKEN: &str = "[table]\na|b\n\n[dag]\na {x: 1}\n\n[table]\nc|d\n";
+
+    fn write_one_broken(on_error: OnError) -> (String, Vec<Error>) {
+        let (output, errors) = write_each(parse_each(ONE_BROKEN).unwrap(), on_error);
+        (String::from_utf8(output).unwrap(), errors)
+    }
+
+    #[test]
+    fn skip_leaves_out_only_the_failed_diagram() {
+        let (output, errors) = write_one_broken(OnError::Skip);
+        assert_eq!(
+            output,
+            "+---+---+\n| a | b |\n+---+---+\n\n+---+---+\n| c | d |\n+---+---+\n\n"
+        );
+        assert_eq!(errors.len(), 1);
+        let e = errors[0].downcast_ref::<ScriptError>().unwrap();
+        assert_eq!(e.message, "unknown node attribute: x");
+        assert_eq!(e.tag.as_deref(), Some("dag"));
+        assert_eq!(e.location.as_ref().unwrap().line, 5);
+    }
+
+    #[test]
+    fn inline_draws_the_error_in_place_of_the_failed_diagram() {
+        let (output, errors) = write_one_broken(OnError::Inline);
+        let error_box = "+------------------------------------------+\n\
+                         | parsing error: unknown node attribute: x |\n\
+                         +------------------------------------------+\n";
+        assert_eq!(
+            output,
+            format!(
+                "+---+---+\n| a | b |\n+---+---+\n\n{}\n+---+---+\n| c | d |\n+---+---+\n\n",
+                error_box
+            )
+        );
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn abort_returns_the_error_of_the_failed_diagram() {
+        let e = crate::render_str_with(ONE_BROKEN, OnError::Abort).unwrap_err();
+        let e = e.downcast_ref::<ScriptError>().unwrap();
+        assert_eq!(e.message, "unknown node attribute: x");
+        assert_eq!(e.location.as_ref().unwrap().line, 5);
+        assert!(crate::render_str(ONE_BROKEN).is_err());
+    }
+}
@@ -1,36 +1,44 @@
-use std::iter::repeat;
+use std::iter::repeat_n;
+
+use unicode_width::{UnicodeWidthChar, UnicodeWidthStr};
+
+/// Number of terminal columns a string takes, counting CJK and emoji as two columns
+/// and combining marks as none
+pub fn display_width(s: &str) -> usize {
+    s.width()
+}
 
 pub fn pad_string_center(s: &str, width: usize, l_pad: char, r_pad: char) -> String {
-    if s.len() > width {
+    if display_width(s) > width {
         s.to_owned()
     } else {
-        let total_pad_len = width - s.len();
+        let total_pad_len = width - display_width(s);
         let l_pad_len = total_pad_len / 2;
         let r_pad_len = total_pad_len - l_pad_len;
         format!(
             "{}{}{}",
-            repeat(l_pad).take(l_pad_len).collect::<String>(),
+            repeat_n(l_pad, l_pad_len).collect::<String>(),
             s,
-            repeat(r_pad).take(r_pad_len).collect::<String>()
+            repeat_n(r_pad, r_pad_len).collect::<String>()
         )
     }
 }
 
 pub fn pad_string_right(s: &str, width: usize, r_pad: char) -> String {
-    if s.len() > width {
+    if display_width(s) > width {
         s.to_owned()
     } else {
-        let pad_len = width - s.len();
-        format!("{}{}", s, repeat(r_pad).take(pad_len).collect::<String>())
+        let pad_len = width - display_width(s);
+        format!("{}{}", s, repeat_n(r_pad, pad_len).collect::<String>())
     }
 }
 
 pub fn pad_string_left(s: &str, width: usize, l_pad: char) -> String {
-    if s.len() > width {
+    if display_width(s) > width {
         s.to_owned()
     } else {
-        let pad_len = width - s.len();
-        format!("{}{}", repeat(l_pad).take(pad_len).collect::<String>(), s)
+        let pad_len = width - display_width(s);
+        format!("{}{}", repeat_n(l_pad, pad_len).collect::<String>(), s)
     }
 }
 
@@ -42,3 +50,44 @@ pub fn add_prefix(input: String, prefix: &str) -> String {
         .collect::<Vec<String>>()
         .join("\n")
 }
+
+/// Draw an ASCII box around the first line of an error, to stand in for a failed diagram
+pub fn error_box(e: &anyhow::Error) -> String {
+    let message = e.to_string();
+    let message = message.lines().next().unwrap_or_default();
+    let width = display_width(message) + 2;
+    let border = format!("+{}+", "-".repeat(width));
+    format!("{}\n| {} |\n{}\n", border, message, border)
+}
+
+/// Replace the `\/` and `\#` escapes, which keep a value from starting a comment
+pub fn unescape(s: &str) -> String {
+    s.replace("\\/", "/").replace("\\#", "#")
+}
+
+/// Break text into lines at most `width` columns wide, between words where possible
+pub fn wrap_text(s: &str, width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = String::new();
+    for word in s.split_whitespace() {
+        if !line.is_empty() && display_width(&line) + 1 + display_width(word) <= width {
+            line.push(' ');
+            line.push_str(word);
+            continue;
+        }
+        if !line.is_empty() {
+            lines.push(std::mem::take(&mut line));
+        }
+        // Words wider than a line are broken anywhere
+        for c in word.chars() {
+            if !line.is_empty() && display_width(&line) + c.width().unwrap_or(0) > width {
+                lines.push(std::mem::take(&mut line));
+            }
+            line.push(c);
+        }
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
@@ -1,10 +1,10 @@
-//! WIP
-
 use std::{
-    fs::{metadata, Metadata},
-    io::{stdout, Write},
-    thread::sleep,
-    time::Duration,
+    cmp::min,
+    collections::hash_map::DefaultHasher,
+    fs,
+    hash::{Hash, Hasher},
+    io::{stdout, Stdout, Write},
+    time::{Duration, Instant, SystemTime},
 };
 
 use anyhow::{Error, Result};
@@ -12,76 +12,273 @@ use crossterm::{
     cursor::{self, MoveTo},
     event::{self, KeyCode, KeyModifiers},
     execute, queue,
-    style::Print,
-    terminal::{self, Clear},
+    style::{Attribute, Print, SetAttribute},
+    terminal::{self, Clear, ClearType},
 };
+use mono_diagram::{add_prefix, display_width, error::with_path, parse_str, write};
 
-use crate::{
-    parser::{parse, write},
-    utils::add_prefix,
-};
+/// How often the file is checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+/// How long the file must stay unchanged before it is rendered again
+const DEBOUNCE: Duration = Duration::from_millis(200);
 
 pub fn watch(file: &str, prefix: Option<String>) -> Result<()> {
+    if file == "-" {
+        return Err(Error::msg(
+            "watch error: cannot watch stdin, please specify a file",
+        ));
+    }
     let mut stdout = stdout();
-    terminal::enable_raw_mode()?;
-    execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
-    let mut is_running = true;
-    let mut init_metadata;
-    while is_running {
-        execute!(stdout, Clear(terminal::ClearType::All), MoveTo(0, 0))?;
-        let result = parse(file).and_then(|d| write(&d));
-        match result {
-            Ok(d) => {
-                println!(
-                    "{}",
-                    add_prefix(
-                        String::from_utf8_lossy(&d).to_string(),
-                        &prefix.clone().unwrap_or("".to_string())
-                    )
-                );
+    let _guard = RawScreen::enter(&mut stdout)?;
+    watch_loop(&mut stdout, file, &prefix.unwrap_or_default())
+}
+
+/// Raw mode on the alternate screen, left again when dropped, even by a panic while
+/// rendering
+struct RawScreen;
+
+impl RawScreen {
+    fn enter(stdout: &mut Stdout) -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        // From here on the guard restores the terminal, even if entering fails halfway
+        let guard = RawScreen;
+        execute!(stdout, terminal::EnterAlternateScreen, cursor::Hide)?;
+        Ok(guard)
+    }
+}
+
+impl Drop for RawScreen {
+    fn drop(&mut self) {
+        let _ = execute!(stdout(), cursor::Show, terminal::LeaveAlternateScreen);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+fn watch_loop(stdout: &mut Stdout, file: &str, prefix: &str) -> Result<()> {
+    let mut preview = Preview::default();
+    let mut rendered: Option<Fingerprint> = None;
+    let mut last_seen: Option<Fingerprint> = None;
+    let mut last_change = Instant::now();
+    let mut dirty = true;
+
+    loop {
+        // Re-render once the file has settled down, so rapid saves only render once
+        match fingerprint(file) {
+            Ok((fp, content)) => {
+                if last_seen.as_ref() != Some(&fp) {
+                    last_seen = Some(fp);
+                    last_change = Instant::now();
+                }
+                if rendered.is_none()
+                    || rendered.as_ref().map(|r| r.hash) != last_seen.as_ref().map(|l| l.hash)
+                        && last_change.elapsed() >= DEBOUNCE
+                {
+                    preview.update(&content, file, prefix);
+                    rendered = last_seen.clone();
+                    dirty = true;
+                }
             }
             Err(e) => {
-                println!("{}", e)
+                // The file may be missing for a moment while an editor replaces it
+                let msg = e.to_string();
+                if preview.error.as_ref() != Some(&msg) {
+                    preview.error = Some(msg);
+                    rendered = None;
+                    dirty = true;
+                }
             }
         }
 
-        stdout.flush()?;
-        init_metadata =
-            metadata(file).map_err(|_| Error::msg("fail to fetch metadata of the file"))?;
-
-        while !file_changed(file, &init_metadata)? {
-            if event::poll(Duration::ZERO)? {
-                match event::read()? {
-                    event::Event::Key(e) => {
-                        if e.code == KeyCode::Esc
-                            || (e.modifiers == KeyModifiers::CONTROL
-                                && e.code == KeyCode::Char('c'))
-                        {
-                            is_running = false;
-                            break;
-                        }
-                    }
-                    event::Event::Resize(_, _) => {
-                        break;
+        if dirty {
+            preview.draw(stdout, file)?;
+            dirty = false;
+        }
+
+        if event::poll(POLL_INTERVAL)? {
+            match event::read()? {
+                event::Event::Key(e) => {
+                    let (_, height) = terminal::size()?;
+                    let page = height.saturating_sub(1).max(1) as isize;
+                    match e.code {
+                        KeyCode::Esc | KeyCode::Char('q') => break,
+                        KeyCode::Char('c') if e.modifiers == KeyModifiers::CONTROL => break,
+                        KeyCode::Up | KeyCode::Char('k') => preview.scroll(-1, 0),
+                        KeyCode::Down | KeyCode::Char('j') => preview.scroll(1, 0),
+                        KeyCode::Left | KeyCode::Char('h') => preview.scroll(0, -1),
+                        KeyCode::Right | KeyCode::Char('l') => preview.scroll(0, 1),
+                        KeyCode::PageUp => preview.scroll(-page, 0),
+                        KeyCode::PageDown | KeyCode::Char(' ') => preview.scroll(page, 0),
+                        KeyCode::Home | KeyCode::Char('g') => preview.offset = (0, 0),
+                        KeyCode::End | KeyCode::Char('G') => preview.scroll(isize::MAX, 0),
+                        _ => continue,
                     }
-                    _ => {}
-                };
+                    dirty = true;
+                }
+                event::Event::Resize(_, _) => dirty = true,
+                _ => {}
             }
         }
     }
 
-    execute!(stdout, cursor::Show, terminal::LeaveAlternateScreen)?;
-    terminal::disable_raw_mode()?;
-
     Ok(())
 }
 
-fn file_changed(file: &str, prev_metadata: &Metadata) -> Result<bool> {
-    let cur_metadata =
-        metadata(file).map_err(|_| Error::msg("fail to fetch metadata of the file"))?;
-    if cur_metadata.len() != prev_metadata.len() {
-        return Ok(true);
-    } else {
-        return Ok(false);
+/// State of the live preview
+#[derive(Default)]
+struct Preview {
+    /// Lines of the last successful render
+    lines: Vec<String>,
+    /// Error of the latest render, if it failed
+    error: Option<String>,
+    /// Scrolling offset (row, column)
+    offset: (usize, usize),
+}
+
+impl Preview {
+    fn update(&mut self, content: &str, file: &str, prefix: &str) {
+        match parse_str(content)
+            .map_err(|e| with_path(e, file))
+            .and_then(|d| write(&d))
+        {
+            Ok(d) => {
+                self.lines = add_prefix(String::from_utf8_lossy(&d).to_string(), prefix)
+                    .lines()
+                    .map(|l| l.to_string())
+                    .collect();
+                self.error = None;
+                self.scroll(0, 0);
+            }
+            Err(e) => self.error = Some(e.to_string()),
+        }
+    }
+
+    fn scroll(&mut self, rows: isize, cols: isize) {
+        let (width, height) = terminal::size().unwrap_or((80, 24));
+        let max_row = self
+            .lines
+            .len()
+            .saturating_sub(height.saturating_sub(1) as usize);
+        let max_col = self
+            .lines
+            .iter()
+            .map(|l| display_width(l))
+            .max()
+            .unwrap_or(0)
+            .saturating_sub(width as usize);
+        self.offset.0 = self.offset.0.saturating_add_signed(rows).min(max_row);
+        self.offset.1 = self.offset.1.saturating_add_signed(cols).min(max_col);
+    }
+
+    fn draw(&self, stdout: &mut Stdout, file: &str) -> Result<()> {
+        let (width, height) = terminal::size()?;
+        let (width, height) = (width as usize, height as usize);
+        let view_height = height.saturating_sub(1);
+        queue!(stdout, Clear(ClearType::All))?;
+        for (row, line) in self
+            .lines
+            .iter()
+            .skip(self.offset.0)
+            .take(view_height)
+            .enumerate()
+        {
+            let visible = visible_part(line, self.offset.1, width);
+            queue!(stdout, MoveTo(0, row as u16), Print(visible))?;
+        }
+
+        // Status line
+        let status = match &self.error {
+            // Message and position of the error
+            Some(e) => format!(
+                " {}",
+                e.lines()
+                    .take(2)
+                    .map(|l| l.trim())
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            ),
+            None => format!(
+                " {} | lines {}-{} of {} | arrows/PgUp/PgDn: scroll, q: quit",
+                file,
+                if self.lines.is_empty() {
+                    0
+                } else {
+                    self.offset.0 + 1
+                },
+                min(self.offset.0 + view_height, self.lines.len()),
+                self.lines.len()
+            ),
+        };
+        let status = visible_part(&status, 0, width);
+        let status = format!(
+            "{}{}",
+            status,
+            " ".repeat(width.saturating_sub(display_width(&status)))
+        );
+        queue!(
+            stdout,
+            MoveTo(0, height.saturating_sub(1) as u16),
+            SetAttribute(Attribute::Reverse),
+            Print(status),
+            SetAttribute(Attribute::Reset)
+        )?;
+        stdout.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Part of a line in the terminal columns `offset..offset + width`
+fn visible_part(line: &str, offset: usize, width: usize) -> String {
+    let mut col = 0;
+    let mut res = String::new();
+    for c in line.chars() {
+        let w = display_width(c.encode_utf8(&mut [0; 4]));
+        if col >= offset && col + w <= offset + width {
+            res.push(c);
+        } else if col < offset && col + w > offset {
+            // A wide character cut by the left edge
+            res.push(' ');
+        }
+        col += w;
+    }
+    res
+}
+
+/// Identifies a version of the watched file
+#[derive(Clone, PartialEq, Eq)]
+struct Fingerprint {
+    modified: Option<SystemTime>,
+    hash: u64,
+}
+
+fn fingerprint(file: &str) -> Result<(Fingerprint, String)> {
+    let modified = fs::metadata(file)
+        .map_err(|_| Error::msg("watch error: fail to fetch metadata of the file"))?
+        .modified()
+        .ok();
+    let content = fs::read_to_string(file)
+        .map_err(|e| Error::msg(format!("watch error: fail to read {}: {}", file, e)))?;
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    Ok((
+        Fingerprint {
+            modified,
+            hash: hasher.finish(),
+        },
+        content,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::visible_part;
+
+    #[test]
+    fn visible_part_counts_terminal_columns() {
+        assert_eq!(visible_part("ab中文cd", 0, 4), "ab中");
+        assert_eq!(visible_part("ab中文cd", 2, 4), "中文");
+        // Wide characters cut by either edge are not drawn in half
+        assert_eq!(visible_part("ab中文cd", 3, 4), " 文c");
+        assert_eq!(visible_part("ab中文cd", 0, 3), "ab");
     }
 }